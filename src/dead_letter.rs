@@ -0,0 +1,233 @@
+//! Dead letters: records which failed processing. Records are annotated with error metadata
+//! and passed to the dead letter handler of step, to the file set in the 'dead_letter_file' param,
+//! or to host if it accepts dead letters, in this order
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use log::{error, warn};
+use once_cell::sync::Lazy;
+
+use crate::{
+    error::Error,
+    ffi::{
+        shared::{emit_dead_letter, get_param},
+        types::module::{ModuleHandle, Record},
+        utils::panic::lock_or_recover,
+    },
+    metrics::counter,
+    record::OwnedRecord,
+    trace::encode_hex,
+};
+
+/// Metadata key with the handle of step which failed to process the record
+pub const METADATA_DLQ_STEP: &str = "dlq_step";
+/// Metadata key with the error code
+pub const METADATA_DLQ_ERROR_CODE: &str = "dlq_error_code";
+/// Metadata key with the error message
+pub const METADATA_DLQ_ERROR: &str = "dlq_error";
+/// Metadata key with the number of processing attempts
+pub const METADATA_DLQ_ATTEMPTS: &str = "dlq_attempts";
+
+/// A step param with the path of file where dead letters of step are appended
+pub const PARAM_DEAD_LETTER_FILE: &str = "dead_letter_file";
+
+static HANDLERS: Lazy<Mutex<HashMap<ModuleHandle, Arc<dyn DeadLetterHandler>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/// File writers opened for the 'dead_letter_file' param. Steps writing to the same file share a writer
+static FILE_WRITERS: Lazy<Mutex<HashMap<PathBuf, Arc<FileDeadLetterWriter>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/// A destination of dead letters
+pub trait DeadLetterHandler: Send + Sync {
+    /// Stores an annotated record. The record is lost if error is returned
+    fn handle(&self, record: OwnedRecord) -> Result<(), Error>;
+}
+
+/// Makes the handler receive dead letters of step instead of file or host
+pub fn set_dead_letter_handler(h: ModuleHandle, handler: Arc<dyn DeadLetterHandler>) {
+    lock_or_recover(&HANDLERS).insert(h, handler);
+}
+
+pub fn remove_dead_letter_handler(h: ModuleHandle) {
+    lock_or_recover(&HANDLERS).remove(&h);
+}
+
+impl Record {
+    /// Adds error metadata to a record which failed processing.
+    /// NUL bytes of error message are replaced, as they cannot be passed in C strings
+    /// ```
+    /// use std::collections::HashMap;
+    /// use torustiq_common::{dead_letter::METADATA_DLQ_ERROR, error::Error, ffi::types::module::Record};
+    /// let mut r = Record::from_std_types(vec![], HashMap::new());
+    /// r.annotate_dead_letter(1, &Error::data("bad\0byte"), 1);
+    /// assert_eq!(r.get_metadata(METADATA_DLQ_ERROR).unwrap().to_str(), Ok("bad byte"));
+    /// r.free_contents();
+    /// ```
+    pub fn annotate_dead_letter(&mut self, h: ModuleHandle, error: &Error, attempts: u32) {
+        self.set_metadata(METADATA_DLQ_STEP, h.to_string());
+        self.set_metadata(METADATA_DLQ_ERROR_CODE, error.code.to_string());
+        self.set_metadata(METADATA_DLQ_ERROR, error.message.replace('\0', " "));
+        self.set_metadata(METADATA_DLQ_ATTEMPTS, attempts.to_string());
+    }
+}
+
+/// Annotates a record which failed processing and hands it off to the dead letter queue.
+/// Returns false if no dead letter queue is available or it failed; the record is lost in this case
+/// ```
+/// use std::{collections::HashMap, sync::{Arc, Mutex}};
+/// use torustiq_common::{dead_letter::*, error::Error, record::OwnedRecord};
+///
+/// struct Collector(Mutex<Vec<OwnedRecord>>);
+///
+/// impl DeadLetterHandler for Collector {
+///     fn handle(&self, record: OwnedRecord) -> Result<(), Error> {
+///         self.0.lock().unwrap().push(record);
+///         Ok(())
+///     }
+/// }
+///
+/// let collector = Arc::new(Collector(Mutex::new(Vec::new())));
+/// set_dead_letter_handler(4, collector.clone());
+/// let record = OwnedRecord::from_std_types(b"{".to_vec(), HashMap::new());
+/// assert!(dead_letter(4, record, &Error::data("invalid JSON").with_code(1001), 1));
+/// let letters = collector.0.lock().unwrap();
+/// assert_eq!(letters[0].get_metadata(METADATA_DLQ_ERROR).unwrap().to_str(), Ok("invalid JSON"));
+/// assert_eq!(letters[0].get_metadata(METADATA_DLQ_ERROR_CODE).unwrap().to_str(), Ok("1001"));
+/// ```
+pub fn dead_letter(h: ModuleHandle, mut record: OwnedRecord, error: &Error, attempts: u32) -> bool {
+    record.annotate_dead_letter(h, error, attempts);
+    let result = match get_handler(h) {
+        Some(handler) => handler.handle(record)
+            .inspect_err(|e| error!("Step {}: failed to store a dead letter: {}", h, e))
+            .is_ok(),
+        None => {
+            let passed = emit_dead_letter(h, record);
+            if !passed {
+                warn!("Step {}: a record is dropped, as no dead letter queue is configured. Error: {}", h, error);
+            }
+            passed
+        },
+    };
+    if result {
+        counter(h, "torustiq_dead_letters_total", "Records passed to dead letter queue").inc();
+    }
+    result
+}
+
+/// Returns the handler set for step, or a file writer if the 'dead_letter_file' param is set
+fn get_handler(h: ModuleHandle) -> Option<Arc<dyn DeadLetterHandler>> {
+    if let Some(handler) = lock_or_recover(&HANDLERS).get(&h) {
+        return Some(handler.clone());
+    }
+    let path = PathBuf::from(get_param(h, PARAM_DEAD_LETTER_FILE)?);
+    let mut writers = lock_or_recover(&FILE_WRITERS);
+    if let Some(w) = writers.get(&path) {
+        return Some(w.clone());
+    }
+    match FileDeadLetterWriter::open(&path) {
+        Ok(w) => {
+            let w = Arc::new(w);
+            writers.insert(path, w.clone());
+            Some(w)
+        },
+        Err(e) => {
+            error!("Step {}: {}", h, e);
+            None
+        },
+    }
+}
+
+/// Appends dead letters to a file, one JSON object per line. The content is stored as a string
+/// if it's valid UTF-8, or as hex otherwise
+/// ```
+/// use std::collections::HashMap;
+/// use torustiq_common::{dead_letter::*, error::Error, ffi::shared::set_param, record::OwnedRecord};
+///
+/// let path = std::env::temp_dir().join(format!("torustiq-dlq-{}.jsonl", std::process::id()));
+/// set_param(5, PARAM_DEAD_LETTER_FILE, path.to_str().unwrap()).unwrap();
+/// let record = OwnedRecord::from_std_types(b"a \"quoted\" line".to_vec(), HashMap::new());
+/// assert!(dead_letter(5, record, &Error::io("connection refused"), 3));
+/// let written = std::fs::read_to_string(&path).unwrap();
+/// std::fs::remove_file(&path).unwrap();
+/// assert!(written.contains(r#""dlq_attempts":"3""#));
+/// assert!(written.ends_with("\"content\":\"a \\\"quoted\\\" line\"}\n"));
+/// ```
+pub struct FileDeadLetterWriter {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileDeadLetterWriter {
+    /// Opens the file for appending. The file is created if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| Error::io(format!("Failed to open dead letter file '{}': {}", path.display(), e)))?;
+        Ok(FileDeadLetterWriter { path, file: Mutex::new(file) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl DeadLetterHandler for FileDeadLetterWriter {
+    fn handle(&self, record: OwnedRecord) -> Result<(), Error> {
+        let line = to_json_line(&record);
+        let mut file = lock_or_recover(&self.file);
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.flush())
+            .map_err(|e| Error::io(format!("Failed to write dead letter file '{}': {}", self.path.display(), e)))
+    }
+}
+
+fn to_json_line(record: &OwnedRecord) -> String {
+    let timestamp_ms = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    // Sorted, so lines are easier to compare
+    let metadata: BTreeMap<String, String> = record.get_metadata_as_hashmap().into_iter().collect();
+    let mut line = format!("{{\"timestamp_ms\":{},\"metadata\":{{", timestamp_ms);
+    for (i, (k, v)) in metadata.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        let _ = write!(line, "{}:{}", json_string(k), json_string(v));
+    }
+    line.push_str("},");
+    match std::str::from_utf8(record.content()) {
+        Ok(s) => { let _ = write!(line, "\"content\":{}", json_string(s)); },
+        Err(_) => { let _ = write!(line, "\"content_hex\":\"{}\"", encode_hex(record.content())); },
+    }
+    line.push_str("}\n");
+    line
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => { let _ = write!(result, "\\u{:04x}", c as u32); },
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
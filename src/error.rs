@@ -0,0 +1,169 @@
+//! An owned Rust representation of module errors
+
+use std::fmt;
+
+use crate::ffi::{
+    types::error::{ErrorCategory, ModuleError},
+    utils::strings::{cchar_const_deallocate, string_to_cchar},
+};
+
+/// Unknown or unspecified error
+pub const ERROR_CODE_UNKNOWN: u32 = 0;
+/// A panic occurred inside module
+pub const ERROR_CODE_PANIC: u32 = 1;
+/// The step is poisoned by an earlier panic and doesn't accept calls anymore
+pub const ERROR_CODE_STEP_POISONED: u32 = 2;
+/// The step is not configured or started
+pub const ERROR_CODE_STEP_NOT_READY: u32 = 3;
+/// The step queue is closed
+pub const ERROR_CODE_QUEUE_CLOSED: u32 = 4;
+/// A lock is poisoned by a panic in another thread
+pub const ERROR_CODE_LOCK_POISONED: u32 = 5;
+/// A param is missing or has invalid value
+pub const ERROR_CODE_INVALID_PARAM: u32 = 6;
+/// The step is draining or terminated and doesn't accept new records
+pub const ERROR_CODE_STEP_NOT_ACCEPTING: u32 = 7;
+/// The smallest error code available to modules
+pub const ERROR_CODE_MODULE_MIN: u32 = 1000;
+
+/// An error occurred in module
+/// ```
+/// use torustiq_common::{error::Error, ffi::types::error::{ErrorCategory, ModuleError}};
+///
+/// let e = Error::config("the value must be positive").with_param("batch_size").with_code(1001);
+/// let mut ffi_error = ModuleError::from(e.clone());
+/// assert_eq!(ffi_error.category, ErrorCategory::Config);
+/// assert_eq!(Error::from(&ffi_error), e);
+/// ffi_error.free_contents();
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub code: u32,
+    pub category: ErrorCategory,
+    pub retryable: bool,
+    pub message: String,
+    pub param_name: Option<String>,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(category: ErrorCategory, message: S) -> Self {
+        Error {
+            code: ERROR_CODE_UNKNOWN,
+            category,
+            retryable: false,
+            message: message.into(),
+            param_name: None,
+        }
+    }
+
+    pub fn config<S: Into<String>>(message: S) -> Self {
+        Error::new(ErrorCategory::Config, message)
+    }
+
+    /// Creates an input / output error. These errors are retryable by default
+    pub fn io<S: Into<String>>(message: S) -> Self {
+        Error::new(ErrorCategory::Io, message).retryable(true)
+    }
+
+    pub fn data<S: Into<String>>(message: S) -> Self {
+        Error::new(ErrorCategory::Data, message)
+    }
+
+    pub fn internal<S: Into<String>>(message: S) -> Self {
+        Error::new(ErrorCategory::Internal, message)
+    }
+
+    /// Creates an error describing a panic inside module
+    pub fn panic<S: Into<String>>(message: S) -> Self {
+        Error::internal(message).with_code(ERROR_CODE_PANIC)
+    }
+
+    pub fn with_code(mut self, code: u32) -> Self {
+        self.code = code;
+        self
+    }
+
+    pub fn retryable(mut self, retryable: bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    pub fn with_param<S: Into<String>>(mut self, param_name: S) -> Self {
+        self.param_name = Some(param_name.into());
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.param_name {
+            Some(p) => write!(f, "{:?} error {} (param '{}'): {}", self.category, self.code, p, self.message),
+            None => write!(f, "{:?} error {}: {}", self.category, self.code, self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<String> for Error {
+    fn from(value: String) -> Self {
+        Error::internal(value)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(value: &str) -> Self {
+        Error::internal(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        let retryable = matches!(value.kind(),
+            ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock |
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::ConnectionRefused);
+        Error::io(value.to_string()).retryable(retryable)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(value: std::string::FromUtf8Error) -> Self {
+        Error::data(value.to_string())
+    }
+}
+
+extern "C" fn free_error_string(c: crate::ffi::types::std_types::ConstCharPtr) {
+    cchar_const_deallocate(c);
+}
+
+/// NB: the output must be deallocated later using 'free_contents'
+impl From<Error> for ModuleError {
+    fn from(value: Error) -> Self {
+        ModuleError {
+            code: value.code,
+            category: value.category,
+            retryable: value.retryable,
+            // NUL characters would make the conversion fail
+            message: string_to_cchar(value.message.replace('\0', " ")),
+            param_name: match value.param_name {
+                Some(p) => string_to_cchar(p.replace('\0', " ")),
+                None => std::ptr::null(),
+            },
+            free_fn: Some(free_error_string),
+        }
+    }
+}
+
+/// Copies the error. The source must still be deallocated with 'free_contents'
+impl From<&ModuleError> for Error {
+    fn from(value: &ModuleError) -> Self {
+        Error {
+            code: value.code,
+            category: value.category,
+            retryable: value.retryable,
+            message: value.message(),
+            param_name: value.param_name(),
+        }
+    }
+}
@@ -1,12 +1,18 @@
-/// Routines which are re-usable in modules
+//! Routines which are re-usable in modules
 
 use std::{collections::HashMap, sync:: Mutex};
 
 use once_cell::sync::Lazy;
 
-use crate::ffi::types::{
-    buffer::free_buf,
-    module as module_types,
+use crate::ffi::{
+    types::{
+        capabilities::{ApiCapabilities, CapabilityFlags, NegotiatedCapabilities},
+        module as module_types,
+    },
+    utils::{
+        panic::lock_or_recover,
+        strings::string_to_cchar,
+    },
 };
 
 #[cfg(feature="export_type__cchar")]
@@ -15,6 +21,8 @@ use crate::ffi::types::std_types::ConstCharPtr;
 #[cfg(feature="export_fn__step_set_param")]
 use crate::ffi::utils::strings::cchar_to_string;
 
+use crate::{error::Error, record::OwnedRecord, secret::SecretString, trace::Span};
+
 use super::types::module::ModuleListenerConfigureArgs;
 
 static COMMON_LIB_CONFIGURATION: Lazy<Mutex<Option<module_types::LibCommonInitArgs>>> = Lazy::new(|| {
@@ -37,141 +45,520 @@ static LISTENER_MODULE_CONFIGURATION: Lazy<Mutex<HashMap<module_types::ModuleHan
     Mutex::new(HashMap::new())
 });
 
+/// API versions and features this module is willing to use. Defaults to everything the library supports
+static MODULE_CAPABILITIES: Lazy<Mutex<ApiCapabilities>> = Lazy::new(|| {
+    Mutex::new(ApiCapabilities::current())
+});
+
+/// Capabilities agreed with host during library initialization
+static NEGOTIATED_CAPABILITIES: Lazy<Mutex<Option<NegotiatedCapabilities>>> = Lazy::new(|| {
+    Mutex::new(None)
+});
+
 /// A 2-dimensional hash map of parameters passed from configuration - like credentials, operating mode, etc
 /// Dimension 1: key = module step handle
 /// Dimension 2: key = parameter name
-static MODULE_PARAMS: Lazy<Mutex<HashMap<module_types::ModuleHandle, HashMap<String, String>>>> = Lazy::new(|| {
+static MODULE_PARAMS: Lazy<Mutex<HashMap<module_types::ModuleHandle, HashMap<String, ParamValue>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/// A value of step param. Secret values are wiped from memory on drop and redacted in debug output
+#[derive(Clone, Debug)]
+enum ParamValue {
+    Plain(String),
+    Secret(SecretString),
+}
+
+impl ParamValue {
+    fn expose(&self) -> &str {
+        match self {
+            ParamValue::Plain(v) => v,
+            ParamValue::Secret(v) => v.expose(),
+        }
+    }
+}
+
+/// Steps which panicked. Key is a step handle, value is a description of panic
+static POISONED_STEPS: Lazy<Mutex<HashMap<module_types::ModuleHandle, String>>> = Lazy::new(|| {
     Mutex::new(HashMap::new())
 });
 
 #[cfg(feature="export_fn__lib_listener_init")]
 #[no_mangle]
-extern "C" fn torustiq_lib_listener_init(a: module_types::LibListenerInitArgs) {
-    use crate::logging::init_logger;
+extern "C" fn torustiq_lib_listener_init(a: module_types::LibListenerInitArgs) -> module_types::LibInitFnResult {
+    use crate::ffi::utils::panic::catch_panic;
 
-    set_listener_lib_configuration(a);
-    init_logger();
+    match catch_panic("torustiq_lib_listener_init", None, || init_listener_lib(a)) {
+        Ok(r) => r,
+        Err(e) => module_types::LibInitFnResult::ErrorMisc(e.into()),
+    }
 }
 
 #[cfg(feature="export_fn__lib_pipeline_init")]
 #[no_mangle]
-extern "C" fn torustiq_lib_pipeline_init(a: module_types::LibPipelineInitArgs) {
-    use crate::logging::init_logger;
+extern "C" fn torustiq_lib_pipeline_init(a: module_types::LibPipelineInitArgs) -> module_types::LibInitFnResult {
+    use crate::ffi::utils::panic::catch_panic;
+
+    match catch_panic("torustiq_lib_pipeline_init", None, || init_pipeline_lib(a)) {
+        Ok(r) => r,
+        Err(e) => module_types::LibInitFnResult::ErrorMisc(e.into()),
+    }
+}
+
+/// Returns params accepted by module. Modules which don't use `export_pipeline_module!` should register
+/// the schema with `params::register_param_schema` before host can call this function, e.g. in `torustiq_lib_get_info`
+#[cfg(feature="export_fn__lib_get_param_schema")]
+#[no_mangle]
+pub extern "C" fn torustiq_lib_get_param_schema() -> crate::ffi::types::params::LibParamSchema {
+    use crate::{ffi::{types::params::LibParamSchema, utils::panic::catch_panic}, params::get_param_schema};
+
+    match catch_panic("torustiq_lib_get_param_schema", None, get_param_schema) {
+        Ok(schema) => LibParamSchema::from(schema.as_ref()),
+        Err(_) => LibParamSchema::from(None),
+    }
+}
 
-    set_pipeline_lib_configuration(a);
-    init_logger();
+/// Returns current values of metrics of all steps
+#[cfg(feature="export_fn__lib_get_metrics")]
+#[no_mangle]
+pub extern "C" fn torustiq_lib_get_metrics() -> crate::ffi::types::collections::Array<crate::ffi::types::metrics::MetricSample> {
+    use crate::{ffi::{types::{collections::Array, metrics::metrics_to_array}, utils::panic::catch_panic}, metrics::snapshot};
+
+    match catch_panic("torustiq_lib_get_metrics", None, || metrics_to_array(&snapshot(None))) {
+        Ok(arr) => arr,
+        Err(_) => Array::from_vec(Vec::new()),
+    }
 }
 
-/// Sets a parameter for step
+/// Sets a parameter for step. The param is validated against the param schema of module, if registered
 #[cfg(feature="export_fn__step_set_param")]
 #[no_mangle]
-pub extern "C" fn torustiq_module_common_set_param(h: module_types::ModuleHandle, k: ConstCharPtr, v: ConstCharPtr) {
-    let mut module_params_container = MODULE_PARAMS.lock().unwrap();
-    if !module_params_container.contains_key(&h) {
-        module_params_container.insert(h, HashMap::new());
+pub extern "C" fn torustiq_module_common_set_param(h: module_types::ModuleHandle, k: ConstCharPtr, v: ConstCharPtr)
+    -> module_types::StepSetParamFnResult {
+    use crate::ffi::utils::panic::catch_panic;
+
+    match catch_panic("torustiq_module_common_set_param", Some(h),
+        || set_param(h, cchar_to_string(k), cchar_to_string(v))).and_then(|r| r) {
+        Ok(_) => module_types::StepSetParamFnResult::Ok,
+        Err(e) => module_types::StepSetParamFnResult::ErrorMisc(e.into()),
     }
-    let step_cfg = module_params_container.get_mut(&h).unwrap();
-    step_cfg.insert(cchar_to_string(k), cchar_to_string(v));
 }
 
 /// Called by main application to trigger the shutdown
 #[cfg(feature="export_fn__step_shutdown")]
 #[no_mangle]
 pub extern "C" fn torustiq_module_common_shutdown(h: module_types::ModuleHandle) {
+    use crate::{ffi::utils::panic::catch_panic, lifecycle::terminate_step};
+
     // No action except forwarding the termination signal back to the main application.
     // Some modules might need additional action like graceful shutdown, exitting from loops etc
-    use log::error;
-    let cfg = match get_common_lib_configuration() {
-        Some(c) => c,
-        None => {
-            error!("torustiq_module_common_shutdown: Failed to load the library configuration");
-            return;
+    let _ = catch_panic("torustiq_module_common_shutdown", Some(h), || {
+        #[cfg(feature="tokio")]
+        crate::pipeline::tokio_runtime::shutdown_step(h);
+        terminate_step(h, module_types::StepTerminationStatus::Shutdown)
+    });
+}
+
+/// Pauses the step. Records are rejected with the 'busy' status until the step is resumed
+#[cfg(feature="export_fn__step_pause")]
+#[no_mangle]
+pub extern "C" fn torustiq_module_common_pause(h: module_types::ModuleHandle) -> module_types::StepControlFnResult {
+    use crate::{ffi::utils::panic::catch_panic, lifecycle::pause_step};
+
+    step_control_result(catch_panic("torustiq_module_common_pause", Some(h), || pause_step(h)).and_then(|r| r))
+}
+
+/// Resumes a paused step
+#[cfg(feature="export_fn__step_resume")]
+#[no_mangle]
+pub extern "C" fn torustiq_module_common_resume(h: module_types::ModuleHandle) -> module_types::StepControlFnResult {
+    use crate::{ffi::utils::panic::catch_panic, lifecycle::resume_step};
+
+    step_control_result(catch_panic("torustiq_module_common_resume", Some(h), || resume_step(h)).and_then(|r| r))
+}
+
+/// Stops accepting records and closes the step queue, if any. The step terminates with the 'drained' status
+/// once the module receives all queued records, or with the 'drain timeout' status after 'timeout_ms'.
+/// Zero 'timeout_ms' means the step drains without timeout
+#[cfg(feature="export_fn__step_drain")]
+#[no_mangle]
+pub extern "C" fn torustiq_module_common_drain(h: module_types::ModuleHandle, timeout_ms: crate::ffi::types::std_types::Uint)
+    -> module_types::StepControlFnResult {
+    use crate::{ffi::utils::panic::catch_panic, lifecycle::{begin_drain, complete_drain}};
+
+    step_control_result(catch_panic("torustiq_module_common_drain", Some(h), || {
+        begin_drain(h)?;
+        #[cfg(feature="pipeline_queue")]
+        if crate::pipeline::async_process::close_queue(h) {
+            crate::lifecycle::spawn_drain_watchdog(h, std::time::Duration::from_millis(timeout_ms as u64));
+            return Ok(());
         }
-    };
-    (cfg.on_step_terminate_cb)(h);
+        // Nothing is buffered by this library
+        let _ = timeout_ms;
+        complete_drain(h);
+        Ok(())
+    }).and_then(|r| r))
+}
+
+#[cfg(any(feature="export_fn__step_pause", feature="export_fn__step_resume", feature="export_fn__step_drain"))]
+fn step_control_result(r: Result<(), Error>) -> module_types::StepControlFnResult {
+    match r {
+        Ok(_) => module_types::StepControlFnResult::Ok,
+        Err(e) => module_types::StepControlFnResult::ErrorMisc(e.into()),
+    }
 }
 
 /// Deallocates memory for a record
 #[cfg(feature="export_fn__free_record")]
 #[no_mangle]
 pub extern "C" fn torustiq_module_pipeline_free_record(r: module_types::Record) {
-    do_free_record(r);
+    use crate::ffi::utils::panic::catch_panic;
+
+    let _ = catch_panic("torustiq_module_pipeline_free_record", None, || do_free_record(r));
 }
 
 /// Deallocates memory for a C-string
 #[cfg(feature="export_fn__free_char_ptr")]
 #[no_mangle]
 pub extern "C" fn torustiq_module_common_free_char(c: ConstCharPtr) {
-    use super::utils::strings::cchar_const_deallocate;
+    use super::utils::{panic::catch_panic, strings::cchar_const_deallocate};
 
-    cchar_const_deallocate(c);
+    let _ = catch_panic("torustiq_module_common_free_char", None, || cchar_const_deallocate(c));
 }
 
-pub fn do_free_record(r: module_types::Record) {
-    free_buf(r.content);
+/// Returns the poison status of step. Host may isolate a poisoned step instead of shutting down the whole pipeline
+#[cfg(feature="export_fn__step_get_poison_status")]
+#[no_mangle]
+pub extern "C" fn torustiq_module_common_get_poison_status(h: module_types::ModuleHandle) -> module_types::StepPoisonStatus {
+    get_step_poison_status_safe(h)
+}
+
+/// Receives the delivery outcome of record produced by step
+#[cfg(feature="export_fn__record_ack")]
+#[no_mangle]
+pub extern "C" fn torustiq_module_pipeline_record_ack(h: module_types::ModuleHandle, id: module_types::RecordId,
+    status: module_types::RecordAckStatus) {
+    use crate::{ffi::utils::panic::catch_panic, pipeline::acks::on_record_ack};
+
+    let _ = catch_panic("torustiq_module_pipeline_record_ack", Some(h), || on_record_ack(h, id, status));
+}
+
+/// Changes the log level of step
+#[cfg(feature="export_fn__step_set_log_level")]
+#[no_mangle]
+pub extern "C" fn torustiq_module_common_set_log_level(h: module_types::ModuleHandle, level: crate::ffi::types::logging::LogLevel) {
+    use crate::ffi::utils::panic::catch_panic;
+
+    let _ = catch_panic("torustiq_module_common_set_log_level", Some(h),
+        || crate::logging::set_step_log_level(h, level.into()));
+}
+
+/// Negotiates capabilities with host. If host is compatible, stores the library configuration and initializes logging
+pub fn init_pipeline_lib(a: module_types::LibPipelineInitArgs) -> module_types::LibInitFnResult {
+    use crate::logging::init_logger;
+
+    let result = negotiate_capabilities(&a.common.host_capabilities);
+    if let module_types::LibInitFnResult::Ok(_) = result {
+        set_pipeline_lib_configuration(a);
+        init_logger();
+    }
+    result
+}
+
+/// Negotiates capabilities with host. If host is compatible, stores the library configuration and initializes logging
+pub fn init_listener_lib(a: module_types::LibListenerInitArgs) -> module_types::LibInitFnResult {
+    use crate::logging::init_logger;
+
+    let result = negotiate_capabilities(&a.common.host_capabilities);
+    if let module_types::LibInitFnResult::Ok(_) = result {
+        set_listener_lib_configuration(a);
+        init_logger();
+    }
+    result
+}
+
+/// Reports the step termination to the main application.
+/// Use `lifecycle::terminate_step` to make sure host is notified only once
+pub fn notify_step_terminated(h: module_types::ModuleHandle, status: module_types::StepTerminationStatus) {
+    use log::error;
+    let cfg = match get_common_lib_configuration() {
+        Some(c) => c,
+        None => {
+            error!("notify_step_terminated: Failed to load the library configuration");
+            return;
+        }
+    };
+    (cfg.on_step_terminate_cb)(h, status);
+}
+
+/// Deallocates content and metadata of record
+pub fn do_free_record(mut r: module_types::Record) {
+    r.free_contents();
+}
+
+/// Passes a record produced by step to the main application.
+/// Returns false if the library is not initialized as pipeline library; the record is freed in this case
+pub fn emit_record(h: module_types::ModuleHandle, mut r: OwnedRecord) -> bool {
+    match get_pipeline_lib_configuration() {
+        Some(cfg) => {
+            let _span = start_data_receive_span(h, &mut r);
+            adapt_metadata_for_host(&mut r);
+            (cfg.on_data_receive_cb)(h, r.into_raw());
+            true
+        },
+        None => false,
+    }
+}
+
+/// Converts typed metadata into strings if host doesn't support typed metadata
+fn adapt_metadata_for_host(r: &mut OwnedRecord) {
+    use crate::ffi::types::capabilities::CAPABILITY_TYPED_METADATA;
+
+    if !is_capability_enabled(CAPABILITY_TYPED_METADATA) {
+        r.flatten_typed_metadata();
+    }
+}
+
+/// Starts a span around passing the record to host. The next steps continue the trace from this span
+fn start_data_receive_span(h: module_types::ModuleHandle, r: &mut OwnedRecord) -> Option<Span> {
+    let span = Span::start_for_record("on_data_receive", h, r)?;
+    r.inject_trace_context(span.context());
+    Some(span)
+}
+
+/// Passes several records to the main application. The batch callback is used if host provides it
+/// and batching is negotiated; otherwise records are passed one by one.
+/// Returns false if the library is not initialized as pipeline library; records are freed in this case
+pub fn emit_records(h: module_types::ModuleHandle, mut records: Vec<OwnedRecord>) -> bool {
+    use crate::ffi::types::{capabilities::CAPABILITY_BATCHING, collections::Array};
+
+    let cfg = match get_pipeline_lib_configuration() {
+        Some(c) => c,
+        None => return false,
+    };
+    // Spans are finished when the whole batch is passed
+    let _spans: Vec<Span> = records.iter_mut().filter_map(|r| start_data_receive_span(h, r)).collect();
+    records.iter_mut().for_each(adapt_metadata_for_host);
+    match cfg.on_data_receive_batch_cb {
+        Some(cb) if is_capability_enabled(CAPABILITY_BATCHING) => {
+            let raw: Vec<module_types::Record> = records.into_iter().map(OwnedRecord::into_raw).collect();
+            // Host takes ownership of records only, so the array is released without items
+            let mut batch = Array::from_vec(raw);
+            cb(h, batch);
+            batch.free_contents();
+        },
+        _ => records.into_iter().for_each(|r| (cfg.on_data_receive_cb)(h, r.into_raw())),
+    }
+    true
+}
+
+/// Reports the delivery outcome of record to the main application, which passes it to the source step.
+/// Returns false if the record is not tracked, or host doesn't support acknowledgements
+pub fn ack_record(h: module_types::ModuleHandle, id: module_types::RecordId, status: module_types::RecordAckStatus) -> bool {
+    use crate::ffi::types::capabilities::CAPABILITY_ACKS;
+
+    if !id.is_tracked() || !is_capability_enabled(CAPABILITY_ACKS) {
+        return false;
+    }
+    match get_pipeline_lib_configuration().and_then(|cfg| cfg.on_record_ack_cb) {
+        Some(cb) => {
+            cb(h, id, status);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Passes a record which failed processing to the main application.
+/// Returns false if host doesn't accept dead letters; the record is freed in this case
+pub fn emit_dead_letter(h: module_types::ModuleHandle, mut r: OwnedRecord) -> bool {
+    use crate::ffi::types::capabilities::CAPABILITY_DEAD_LETTERS;
+
+    if !is_capability_enabled(CAPABILITY_DEAD_LETTERS) {
+        return false;
+    }
+    match get_pipeline_lib_configuration().and_then(|cfg| cfg.on_dead_letter_cb) {
+        Some(cb) => {
+            adapt_metadata_for_host(&mut r);
+            cb(h, r.into_raw());
+            true
+        },
+        None => false,
+    }
 }
 
 pub fn set_listener_lib_configuration(a: module_types::LibListenerInitArgs) {
-    *COMMON_LIB_CONFIGURATION.lock().unwrap() = Some(a.common.clone());
-    *LISTENER_LIB_CONFIGURATION.lock().unwrap() = Some(a);
+    *lock_or_recover(&COMMON_LIB_CONFIGURATION) = Some(a.common.clone());
+    *lock_or_recover(&LISTENER_LIB_CONFIGURATION) = Some(a);
 }
 
 pub fn get_listener_lib_configuration() -> Option<module_types::LibListenerInitArgs> {
-    LISTENER_LIB_CONFIGURATION.lock().unwrap().clone()
+    lock_or_recover(&LISTENER_LIB_CONFIGURATION).clone()
 }
 
 pub fn set_pipeline_lib_configuration(a: module_types::LibPipelineInitArgs) {
-    *COMMON_LIB_CONFIGURATION.lock().unwrap() = Some(a.common.clone());
-    *PIPELINE_LIB_CONFIGURATION.lock().unwrap() = Some(a);
+    *lock_or_recover(&COMMON_LIB_CONFIGURATION) = Some(a.common.clone());
+    *lock_or_recover(&PIPELINE_LIB_CONFIGURATION) = Some(a);
 }
 
 pub fn get_pipeline_lib_configuration() -> Option<module_types::LibPipelineInitArgs> {
-    PIPELINE_LIB_CONFIGURATION.lock().unwrap().clone()
+    lock_or_recover(&PIPELINE_LIB_CONFIGURATION).clone()
 }
 
 pub fn get_common_lib_configuration() -> Option<module_types::LibCommonInitArgs> {
-    COMMON_LIB_CONFIGURATION.lock().unwrap().clone()
+    lock_or_recover(&COMMON_LIB_CONFIGURATION).clone()
+}
+
+/// Restricts the API versions and features of module. Must be called before the library is initialized
+pub fn set_module_capabilities(c: ApiCapabilities) {
+    *lock_or_recover(&MODULE_CAPABILITIES) = c;
+}
+
+pub fn get_module_capabilities() -> ApiCapabilities {
+    *lock_or_recover(&MODULE_CAPABILITIES)
+}
+
+/// Matches host capabilities against the module ones and stores the outcome.
+/// If version ranges don't overlap, the module capabilities are returned with an error, so host can report them
+/// ```
+/// use torustiq_common::ffi::shared::{get_negotiated_capabilities, negotiate_capabilities};
+/// use torustiq_common::ffi::types::{capabilities::*, module::LibInitFnResult};
+/// use torustiq_common::{CURRENT_API_VERSION, MIN_SUPPORTED_API_VERSION};
+///
+/// let old_host = ApiCapabilities { api_version_min: 2, api_version_max: 2, features: SUPPORTED_CAPABILITIES };
+/// match negotiate_capabilities(&old_host) {
+///     LibInitFnResult::ErrorIncompatibleApiVersion(module) => {
+///         assert_eq!((module.api_version_min, module.api_version_max), (MIN_SUPPORTED_API_VERSION, CURRENT_API_VERSION));
+///     },
+///     _ => panic!("version 2 must be rejected"),
+/// }
+/// assert_eq!(get_negotiated_capabilities(), None);
+/// ```
+pub fn negotiate_capabilities(host: &ApiCapabilities) -> module_types::LibInitFnResult {
+    let module = get_module_capabilities();
+    let negotiated = module.negotiate(host);
+    *lock_or_recover(&NEGOTIATED_CAPABILITIES) = negotiated;
+    match negotiated {
+        Some(n) => module_types::LibInitFnResult::Ok(n),
+        None => module_types::LibInitFnResult::ErrorIncompatibleApiVersion(module),
+    }
+}
+
+pub fn get_negotiated_capabilities() -> Option<NegotiatedCapabilities> {
+    *lock_or_recover(&NEGOTIATED_CAPABILITIES)
+}
+
+/// Checks if the optional feature(s) were agreed with host.
+/// Always false if the library isn't initialized yet
+pub fn is_capability_enabled(flags: CapabilityFlags) -> bool {
+    match get_negotiated_capabilities() {
+        Some(n) => n.has(flags),
+        None => false,
+    }
 }
 
 pub fn set_pipeline_module_configuration(a: module_types::ModulePipelineConfigureArgs) {
-    PIPELINE_MODULE_CONFIGURATION.lock().unwrap().insert(a.module_handle, a);
+    lock_or_recover(&PIPELINE_MODULE_CONFIGURATION).insert(a.module_handle, a);
 }
 
 pub fn get_pipeline_module_configuration(h: module_types::ModuleHandle) -> Option<module_types::ModulePipelineConfigureArgs> {
-    let module_params_container = PIPELINE_MODULE_CONFIGURATION.lock().unwrap();
-    match module_params_container.get(&h) {
-        Some(c) => Some(c.clone()),
-        None => None,
-    }
+    let module_params_container = lock_or_recover(&PIPELINE_MODULE_CONFIGURATION);
+    module_params_container.get(&h).cloned()
 }
 
 pub fn set_listener_module_configuration(a: ModuleListenerConfigureArgs) {
-    LISTENER_MODULE_CONFIGURATION.lock().unwrap().insert(a.module_handle, a);
+    lock_or_recover(&LISTENER_MODULE_CONFIGURATION).insert(a.module_handle, a);
 }
 
 pub fn get_listener_module_configuration(h: module_types::ModuleHandle) -> Option<ModuleListenerConfigureArgs> {
-    let module_params_container = LISTENER_MODULE_CONFIGURATION.lock().unwrap();
-    match module_params_container.get(&h) {
-        Some(c) => Some(c.clone()),
-        None => None,
+    let module_params_container = lock_or_recover(&LISTENER_MODULE_CONFIGURATION);
+    module_params_container.get(&h).cloned()
+}
+
+/// Marks the step as poisoned. Poisoned steps reject further records
+pub fn set_step_poisoned<S: Into<String>>(h: module_types::ModuleHandle, reason: S) {
+    lock_or_recover(&POISONED_STEPS).insert(h, reason.into());
+}
+
+pub fn is_step_poisoned(h: module_types::ModuleHandle) -> bool {
+    lock_or_recover(&POISONED_STEPS).contains_key(&h)
+}
+
+/// Returns a description of panic which poisoned the step
+pub fn get_step_poison_reason(h: module_types::ModuleHandle) -> Option<String> {
+    lock_or_recover(&POISONED_STEPS).get(&h).cloned()
+}
+
+/// Returns the poison status in C ABI format.
+/// NB: the reason string must be deallocated by caller
+pub fn get_step_poison_status(h: module_types::ModuleHandle) -> module_types::StepPoisonStatus {
+    match get_step_poison_reason(h) {
+        Some(r) => module_types::StepPoisonStatus::Poisoned(string_to_cchar(r.replace('\0', " "))),
+        None => module_types::StepPoisonStatus::Healthy,
     }
 }
 
-pub fn get_params(h: module_types::ModuleHandle) -> Option<HashMap<String, String>> {
-    let module_params_container = MODULE_PARAMS.lock().unwrap();
-    match module_params_container.get(&h) {
-        Some(params) => Some(params.clone()),
-        None => None,
+/// Same as `get_step_poison_status`, but a panic is reported as the poison status instead of crossing the C ABI
+pub fn get_step_poison_status_safe(h: module_types::ModuleHandle) -> module_types::StepPoisonStatus {
+    use crate::ffi::utils::panic::catch_panic;
+
+    match catch_panic("torustiq_module_common_get_poison_status", None, || get_step_poison_status(h)) {
+        Ok(s) => s,
+        Err(e) => module_types::StepPoisonStatus::Poisoned(string_to_cchar(e.message.replace('\0', " "))),
     }
 }
 
+/// Stores a param of step. Fails if the param is rejected by the param schema of module.
+/// Values of secret params may refer to environment variables ('env:NAME') or files ('file:PATH');
+/// the references are resolved here
+/// ```
+/// use torustiq_common::ffi::shared::{get_param, get_params, set_param};
+/// set_param(3, "db_password", "hunter2").unwrap();
+/// assert_eq!(get_params(3).unwrap().get("db_password").unwrap(), "***");
+/// assert_eq!(get_param(3, "db_password").unwrap(), "hunter2");
+/// ```
+pub fn set_param<S: Into<String>>(h: module_types::ModuleHandle, k: S, v: S) -> Result<(), Error> {
+    use crate::{params::{is_secret_param, validate_param}, secret::resolve_secret};
+
+    let k = k.into();
+    let v = match is_secret_param(&k) {
+        true => ParamValue::Secret(resolve_secret(&k, v.into())?),
+        false => ParamValue::Plain(v.into()),
+    };
+    validate_param(&k, v.expose())?;
+    let mut module_params_container = lock_or_recover(&MODULE_PARAMS);
+    let step_cfg = module_params_container.entry(h).or_default();
+    step_cfg.insert(k, v);
+    Ok(())
+}
+
+/// Returns all params of step. Values of secret params are redacted, so the output can be logged
+pub fn get_params(h: module_types::ModuleHandle) -> Option<HashMap<String, String>> {
+    use crate::secret::REDACTED;
+
+    let module_params_container = lock_or_recover(&MODULE_PARAMS);
+    module_params_container.get(&h).map(|params| params.iter()
+        .map(|(k, v)| match v {
+            ParamValue::Plain(v) => (k.clone(), v.clone()),
+            ParamValue::Secret(_) => (k.clone(), REDACTED.to_string()),
+        })
+        .collect())
+}
+
+/// Returns a param value. Use `get_secret_param` for secrets, so the value is wiped from memory after use
 pub fn get_param<S: Into<String>>(h: module_types::ModuleHandle, k: S) -> Option<String> {
-    let module_params_container = MODULE_PARAMS.lock().unwrap();
+    let module_params_container = lock_or_recover(&MODULE_PARAMS);
     match module_params_container.get(&h) {
-        Some(params) => match params.get(&(k.into())) {
-            Some(s) => Some(s.clone()),
-            None => None,
-        },
+        Some(params) => params.get(&(k.into())).map(|v| v.expose().to_string()),
         None => None,
     }
+}
+
+/// Returns a param value in a container which is wiped from memory on drop
+pub fn get_secret_param<S: Into<String>>(h: module_types::ModuleHandle, k: S) -> Option<SecretString> {
+    let module_params_container = lock_or_recover(&MODULE_PARAMS);
+    match module_params_container.get(&h)?.get(&(k.into()))? {
+        ParamValue::Plain(v) => Some(SecretString::from(v.clone())),
+        ParamValue::Secret(v) => Some(v.clone()),
+    }
 }
\ No newline at end of file
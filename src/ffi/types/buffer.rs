@@ -1,10 +1,46 @@
+use std::{ffi::c_void, sync::Arc};
+
 use crate::ffi::utils::strings::bytes_to_string_safe;
 
+/// Deallocates bytes of buffer. Arguments are a pointer to bytes and length
+pub type ByteBufferFreeFn = extern "C" fn(*mut u8, usize);
+
+/// Increments or decrements the reference counter of a shared buffer. The argument is the owner of bytes
+pub type ByteBufferRefCountFn = extern "C" fn(*mut c_void);
+
+/// A byte array which can be passed across the C ABI.
+/// The buffer carries a function which releases its memory, so it can be freed by any library,
+/// not only by the one which allocated it. A null function means the buffer doesn't own the bytes.
+///
+/// A buffer might be shared: in this case `owner` points to a reference-counted storage of bytes,
+/// `retain_fn` adds a reference and `release_fn` drops one. Bytes of shared buffer are read-only.
+/// Exclusive buffers have null `owner` and no reference counting functions
 #[repr(C)]
 #[derive(Copy)]
 pub struct ByteBuffer {
     pub bytes: *mut u8,
     pub len: usize,
+    pub free_fn: Option<ByteBufferFreeFn>,
+    pub owner: *mut c_void,
+    pub retain_fn: Option<ByteBufferRefCountFn>,
+    pub release_fn: Option<ByteBufferRefCountFn>,
+}
+
+/// Releases bytes allocated by Rust allocator of this library
+extern "C" fn free_rust_bytes(bytes: *mut u8, len: usize) {
+    let s = std::ptr::slice_from_raw_parts_mut(bytes, len);
+    let _ = unsafe { Box::from_raw(s) };
+}
+
+/// Storage of shared buffers allocated by this library
+type SharedBytes = Box<[u8]>;
+
+extern "C" fn retain_rust_shared(owner: *mut c_void) {
+    unsafe { Arc::increment_strong_count(owner as *const SharedBytes) };
+}
+
+extern "C" fn release_rust_shared(owner: *mut c_void) {
+    unsafe { Arc::decrement_strong_count(owner as *const SharedBytes) };
 }
 
 impl ByteBuffer {
@@ -12,11 +48,15 @@ impl ByteBuffer {
         unsafe { std::mem::transmute(self.bytes) }
     }
 
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(&self) -> String {
         bytes_to_string_safe(self.bytes, self.len)
     }
 
     pub fn to_byte_vec(&self) -> Vec<u8> {
+        if self.bytes.is_null() {
+            return Vec::new();
+        }
         let mut dst: Vec<u8> = Vec::with_capacity(self.len);
         unsafe {
             std::ptr::copy(self.bytes, dst.as_mut_ptr(), self.len);
@@ -26,56 +66,122 @@ impl ByteBuffer {
         dst
     }
 
+    /// Deallocates bytes using the free function of buffer. Calling this function again has no effect
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use torustiq_common::ffi::types::buffer::ByteBuffer;
+    ///
+    /// static FREED_BYTES: AtomicUsize = AtomicUsize::new(0);
+    /// // E.g. a function provided by a C++ module
+    /// extern "C" fn free_foreign(_bytes: *mut u8, len: usize) {
+    ///     FREED_BYTES.fetch_add(len, Ordering::SeqCst);
+    /// }
+    ///
+    /// let bytes = Box::leak(vec![1u8, 2, 3].into_boxed_slice()).as_mut_ptr();
+    /// let mut buf = ByteBuffer {
+    ///     bytes, len: 3, free_fn: Some(free_foreign),
+    ///     owner: std::ptr::null_mut(), retain_fn: None, release_fn: None,
+    /// };
+    /// buf.free_contents();
+    /// buf.free_contents();
+    /// assert_eq!(FREED_BYTES.load(Ordering::SeqCst), 3);
+    /// ```
     pub fn free_contents(&mut self) {
-        let _ = unsafe { Box::from_raw(self.bytes) };
+        if self.bytes.is_null() {
+            return;
+        }
+        match (self.release_fn, self.free_fn) {
+            (Some(release_fn), _) => release_fn(self.owner),
+            (None, Some(free_fn)) => free_fn(self.bytes, self.len),
+            (None, None) => {},
+        }
+        self.bytes = std::ptr::null_mut();
         self.len = 0;
+        self.owner = std::ptr::null_mut();
+    }
+
+    /// Creates a shared buffer. The vector is moved into a reference-counted storage without copying
+    pub fn new_shared(bytes: Vec<u8>) -> ByteBuffer {
+        let storage: Arc<SharedBytes> = Arc::new(bytes.into_boxed_slice());
+        let len = storage.len();
+        let bytes = storage.as_ptr() as *mut u8;
+        ByteBuffer {
+            bytes,
+            len,
+            free_fn: None,
+            owner: Arc::into_raw(storage) as *mut c_void,
+            retain_fn: Some(retain_rust_shared),
+            release_fn: Some(release_rust_shared),
+        }
+    }
+
+    /// Returns true if the buffer is reference-counted
+    pub fn is_shared(&self) -> bool {
+        self.retain_fn.is_some() && self.release_fn.is_some()
+    }
+
+    /// Returns another reference to the same bytes. Each reference must be freed separately.
+    /// Only shared buffers are referenced without copying; exclusive ones are copied
+    /// ```
+    /// use torustiq_common::ffi::types::buffer::ByteBuffer;
+    /// let mut src = ByteBuffer::new_shared(b"payload".to_vec());
+    /// let mut branches: Vec<ByteBuffer> = (0..3).map(|_| src.share()).collect();
+    /// src.free_contents();
+    /// for b in branches.iter_mut() {
+    ///     assert_eq!(b.to_string(), "payload");
+    ///     b.free_contents();
+    /// }
+    /// ```
+    pub fn share(&self) -> ByteBuffer {
+        if self.bytes.is_null() || !self.is_shared() {
+            return ByteBuffer::from(self.to_byte_vec());
+        }
+        (self.retain_fn.unwrap())(self.owner);
+        *self
+    }
+
+    /// Converts the buffer into a shared one. Exclusive buffers are copied once into a shared storage
+    pub fn into_shared(mut self) -> ByteBuffer {
+        if self.is_shared() {
+            return self;
+        }
+        let shared = ByteBuffer::new_shared(self.to_byte_vec());
+        self.free_contents();
+        shared
     }
 }
 
+// Cloning makes a deep copy of bytes, so the clone can be freed independently.
+// Use `share` to reference bytes of a shared buffer without copying
+#[allow(clippy::non_canonical_clone_impl)]
 impl Clone for ByteBuffer {
     fn clone(&self) -> Self {
-        let mut bytes: Vec<u8> = Vec::with_capacity(self.len);
-        unsafe { std::ptr::copy(self.bytes, bytes.as_mut_ptr(), self.len) };
-        let ptr = bytes.as_mut_ptr();
-        std::mem::forget(bytes);
-        ByteBuffer {
-            len: self.len,
-            bytes: ptr,
-        }
+        ByteBuffer::from(self.to_byte_vec())
     }
 }
 
+/// Takes ownership of vector without copying the bytes
 impl From<Vec<u8>> for ByteBuffer {
     fn from(value: Vec<u8>) -> Self {
-        let mut dst: Vec<u8> = value.clone();
-        let bytes = dst.as_mut_ptr();
-        std::mem::forget(dst);
-
+        let len = value.len();
+        let bytes = Box::into_raw(value.into_boxed_slice()) as *mut u8;
         ByteBuffer {
             bytes,
-            len: value.len(),
+            len,
+            free_fn: Some(free_rust_bytes),
+            owner: std::ptr::null_mut(),
+            retain_fn: None,
+            release_fn: None,
         }
     }
 }
 
 impl From<String> for ByteBuffer {
     fn from(input: String) -> Self {
-        let mut dst: Vec<u8> = Vec::with_capacity(input.len());
-        unsafe { std::ptr::copy(input.as_ptr(), dst.as_mut_ptr(), input.len()) };
-        let bytes = dst.as_mut_ptr();
-        std::mem::forget(dst);
-
-        ByteBuffer {
-            bytes,
-            len: input.len(),
-        }
+        ByteBuffer::from(input.into_bytes())
     }
 }
 
-pub extern "C" fn free_buf(buf: ByteBuffer) {
-    let s = unsafe { std::slice::from_raw_parts_mut(buf.bytes, buf.len) };
-    let s = s.as_mut_ptr();
-    unsafe {
-        let _ = Box::from_raw(s);
-    }
+pub extern "C" fn free_buf(mut buf: ByteBuffer) {
+    buf.free_contents();
 }
\ No newline at end of file
@@ -0,0 +1,77 @@
+use crate::ffi::types::std_types;
+use crate::{CURRENT_API_VERSION, MIN_SUPPORTED_API_VERSION};
+
+/// A bitset of optional ABI features. Flags are defined as CAPABILITY_* constants
+pub type CapabilityFlags = u64;
+
+/// Record batches can be passed in a single call
+pub const CAPABILITY_BATCHING: CapabilityFlags = 1 << 0;
+/// Records can be acknowledged by destination steps
+pub const CAPABILITY_ACKS: CapabilityFlags = 1 << 1;
+/// Record metadata can carry typed values in addition to strings
+pub const CAPABILITY_TYPED_METADATA: CapabilityFlags = 1 << 2;
+/// Records which failed processing can be passed to host as dead letters
+pub const CAPABILITY_DEAD_LETTERS: CapabilityFlags = 1 << 3;
+
+/// Optional features implemented by this version of library
+pub const SUPPORTED_CAPABILITIES: CapabilityFlags = CAPABILITY_BATCHING | CAPABILITY_ACKS | CAPABILITY_TYPED_METADATA
+    | CAPABILITY_DEAD_LETTERS;
+
+/// A range of supported API versions plus a set of optional features.
+/// Host passes its own capabilities to module on initialization; module responds
+/// with the intersection of both sides
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApiCapabilities {
+    pub api_version_min: std_types::Uint,
+    pub api_version_max: std_types::Uint,
+    pub features: CapabilityFlags,
+}
+
+/// An outcome of capability negotiation: the API version and features both sides agreed on
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NegotiatedCapabilities {
+    pub api_version: std_types::Uint,
+    pub features: CapabilityFlags,
+}
+
+impl NegotiatedCapabilities {
+    /// Checks if all provided feature flags are enabled
+    pub fn has(&self, flags: CapabilityFlags) -> bool {
+        self.features & flags == flags
+    }
+}
+
+impl ApiCapabilities {
+    /// Capabilities of the current version of library
+    pub fn current() -> Self {
+        ApiCapabilities {
+            api_version_min: MIN_SUPPORTED_API_VERSION,
+            api_version_max: CURRENT_API_VERSION,
+            features: SUPPORTED_CAPABILITIES,
+        }
+    }
+
+    /// Picks the highest API version supported by both sides and the common subset of features.
+    /// Returns None if version ranges don't overlap
+    /// ```
+    /// use torustiq_common::ffi::types::capabilities::*;
+    /// let host = ApiCapabilities { api_version_min: 1, api_version_max: 3, features: CAPABILITY_BATCHING | CAPABILITY_ACKS };
+    /// let module = ApiCapabilities { api_version_min: 2, api_version_max: 2, features: CAPABILITY_ACKS };
+    /// assert_eq!(host.negotiate(&module),
+    ///            Some(NegotiatedCapabilities { api_version: 2, features: CAPABILITY_ACKS }));
+    /// let legacy = ApiCapabilities { api_version_min: 4, api_version_max: 5, features: 0 };
+    /// assert_eq!(host.negotiate(&legacy), None);
+    /// ```
+    pub fn negotiate(&self, other: &ApiCapabilities) -> Option<NegotiatedCapabilities> {
+        let api_version = self.api_version_max.min(other.api_version_max);
+        if api_version < self.api_version_min.max(other.api_version_min) {
+            return None;
+        }
+        Some(NegotiatedCapabilities {
+            api_version,
+            features: self.features & other.features,
+        })
+    }
+}
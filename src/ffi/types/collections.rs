@@ -1,38 +1,73 @@
 use crate::ffi::types::std_types;
 
+/// Deallocates items of array and the array itself. Arguments are a pointer to data and length
+pub type ArrayFreeFn<T> = extern "C" fn(*mut T, std_types::Uint);
+
+/// An array which can be passed across the C ABI.
+/// Like ByteBuffer, the array carries a function which releases its memory,
+/// including the memory owned by items. A null function means the array doesn't own the data
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Array<T> {
     pub data: *mut T,
     pub len: std_types::Uint,
+    pub free_fn: Option<ArrayFreeFn<T>>,
 }
 
-impl<T> Array<T> {
+/// Releases an array allocated by Rust allocator of this library
+pub extern "C" fn free_rust_array<T>(data: *mut T, len: std_types::Uint) {
+    let s = std::ptr::slice_from_raw_parts_mut(data, len as usize);
+    let _ = unsafe { Box::from_raw(s) };
+}
+
+impl<T: Default> Array<T> {
+    /// Creates an array of default items, so the free function never drops uninitialized memory
+    /// ```
+    /// use torustiq_common::ffi::types::collections::Array;
+    /// let mut arr: Array<u32> = Array::new_of_len(3);
+    /// assert_eq!(arr.as_slice(), &[0, 0, 0]);
+    /// arr.free_contents();
+    /// ```
     pub fn new_of_len(len: usize) -> Array<T> {
-        let mut data: Vec<T> = Vec::with_capacity(len);
-        Array {
-            data: unsafe { std::slice::from_raw_parts_mut(data.as_mut_ptr(), std::mem::size_of::<T>() * len).as_mut_ptr() },
-            len: len as std_types::Uint,
-        }
+        Self::from_vec((0..len).map(|_| T::default()).collect())
     }
+}
 
+impl<T> Array<T> {
     pub fn from_vec(vector: Vec<T>) -> Array<T> {
+        Self::from_vec_with_free_fn(vector, free_rust_array::<T>)
+    }
+
+    /// Creates an array with a custom free function, e.g. the one which also releases memory owned by items.
+    /// The function must release the boxed slice the same way as `free_rust_array` does
+    pub fn from_vec_with_free_fn(vector: Vec<T>, free_fn: ArrayFreeFn<T>) -> Array<T> {
         let len = vector.len() as std_types::Uint;
         let mut boxed_slice: Box<[T]> = vector.into_boxed_slice();
         let arr: Array<T> = Array {
             data: boxed_slice.as_mut_ptr(),
             len,
+            free_fn: Some(free_fn),
         };
         std::mem::forget(boxed_slice);
         arr
     }
 
+    /// Returns array items as slice
+    pub fn as_slice(&self) -> &[T] {
+        if self.data.is_null() || self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.data, self.len as usize) }
+    }
+
+    /// Deallocates the array using its free function. Calling this function again has no effect
     pub fn free_contents(&mut self) {
-        let s = unsafe { std::slice::from_raw_parts_mut(self.data, self.len as usize) };
-        let s = s.as_mut_ptr();
-        unsafe {
-            let _ = Box::from_raw(s);
+        if !self.data.is_null() {
+            if let Some(free_fn) = self.free_fn {
+                free_fn(self.data, self.len);
+            }
         }
+        self.data = std::ptr::null_mut();
         self.len = 0;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,66 @@
+use crate::ffi::{
+    types::{functions as fn_defs, std_types},
+    utils::strings::cchar_to_string,
+};
+
+/// A kind of error. Helps host to decide whether to retry, dead-letter or abort
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Invalid or missing configuration, e.g. a step param
+    Config,
+    /// Input / output errors: network, file system, external services
+    Io,
+    /// Malformed record content or metadata
+    Data,
+    /// Unexpected errors inside module, including panics
+    Internal,
+}
+
+/// An error passed across the C ABI.
+/// Like ByteBuffer, the error carries a function which releases its strings,
+/// so the receiving side frees it with `free_contents` regardless of which library created it
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ModuleError {
+    /// An error code. Codes below ERROR_CODE_MODULE_MIN are reserved for this library
+    pub code: std_types::Uint,
+    pub category: ErrorCategory,
+    /// The operation might succeed if retried later
+    pub retryable: bool,
+    pub message: std_types::ConstCharPtr,
+    /// A name of step param which caused the error. Null if not applicable
+    pub param_name: std_types::ConstCharPtr,
+    pub free_fn: Option<fn_defs::ModuleFreeCharPtrFn>,
+}
+
+impl ModuleError {
+    /// Deallocates strings using the free function of error. Calling this function again has no effect
+    pub fn free_contents(&mut self) {
+        if let Some(free_fn) = self.free_fn {
+            if !self.message.is_null() {
+                free_fn(self.message);
+            }
+            if !self.param_name.is_null() {
+                free_fn(self.param_name);
+            }
+        }
+        self.message = std::ptr::null();
+        self.param_name = std::ptr::null();
+    }
+
+    /// Returns the error message. Empty if there is no message
+    pub fn message(&self) -> String {
+        match self.message.is_null() {
+            true => String::new(),
+            false => cchar_to_string(self.message),
+        }
+    }
+
+    pub fn param_name(&self) -> Option<String> {
+        match self.param_name.is_null() {
+            true => None,
+            false => Some(cchar_to_string(self.param_name)),
+        }
+    }
+}
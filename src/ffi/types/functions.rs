@@ -1,43 +1,83 @@
-use crate::ffi::types::module::LibInfo;
+use crate::ffi::types::{
+    logging::{LogLevel, LogRecord},
+    metrics::MetricSample,
+    module::LibInfo,
+    params::LibParamSchema,
+};
 
 use crate::ffi::types::{
+    collections::Array,
     module as module_types,
     std_types,
 };
 
 // Pipeline library functions
-pub type LibGetInfoFn = extern fn() -> LibInfo;
-pub type LibPipelineInitFn = extern fn(module_types::LibPipelineInitArgs);
+pub type LibGetInfoFn = extern "C" fn() -> LibInfo;
+/// Returns params accepted by module. Can be called before the library is initialized
+pub type LibGetParamSchemaFn = extern "C" fn() -> LibParamSchema;
+/// Returns current values of metrics of all steps
+pub type LibGetMetricsFn = extern "C" fn() -> Array<MetricSample>;
+pub type LibPipelineInitFn = extern "C" fn(module_types::LibPipelineInitArgs) -> module_types::LibInitFnResult;
 
 // Listener library functions
 
 // Listener module routines
-pub type ModuleListenerConfigureFn = extern fn(module_types::ModuleListenerConfigureArgs) -> module_types::ModuleListenerConfigureFnResult;
-pub type LibListenerInitFn = extern fn(module_types::LibListenerInitArgs);
-pub type ModuleListenerRecordRcvFn = extern fn(module_types::ModuleHandle, *const module_types::Record);
-pub type ModuleListenerRecordSendSuccessFn = extern fn(module_types::ModuleHandle, *const module_types::Record);
-pub type ModuleListenerRecordSendFailureFn = extern fn(module_types::ModuleHandle, *const module_types::Record);
+pub type ModuleListenerConfigureFn = extern "C" fn(module_types::ModuleListenerConfigureArgs) -> module_types::ModuleListenerConfigureFnResult;
+pub type LibListenerInitFn = extern "C" fn(module_types::LibListenerInitArgs) -> module_types::LibInitFnResult;
+pub type ModuleListenerRecordRcvFn = extern "C" fn(module_types::ModuleHandle, *const module_types::Record);
+pub type ModuleListenerRecordSendSuccessFn = extern "C" fn(module_types::ModuleHandle, *const module_types::Record);
+pub type ModuleListenerRecordSendFailureFn = extern "C" fn(module_types::ModuleHandle, *const module_types::Record);
 
 /// Passes a configuration to step
-pub type ModulePipelineConfigureFn = extern fn(module_types::ModulePipelineConfigureArgs) -> module_types::ModulePipelineConfigureFnResult;
-pub type ModulePipelineProcessRecordFn = extern fn (module_types::ModuleHandle, module_types::Record) -> module_types::ModulePipelineProcessRecordFnResult;
+pub type ModulePipelineConfigureFn = extern "C" fn(module_types::ModulePipelineConfigureArgs) -> module_types::ModulePipelineConfigureFnResult;
+pub type ModulePipelineProcessRecordFn = extern "C" fn(module_types::ModuleHandle, module_types::Record) -> module_types::ModulePipelineProcessRecordFnResult;
+/// Processes a batch of records in a single call. See ModulePipelineProcessRecordsFnResult for ownership rules
+pub type ModulePipelineProcessRecordsFn = extern "C" fn(module_types::ModuleHandle, Array<module_types::Record>) -> module_types::ModulePipelineProcessRecordsFnResult;
+/// Passes the delivery outcome of record to the source step which produced it
+pub type ModulePipelineRecordAckFn = extern "C" fn(module_types::ModuleHandle, module_types::RecordId, module_types::RecordAckStatus);
 /// Starts the routines inside step (opens HTTP connections, generator threads, message broker consumers, etc)
 /// After calling this function the step is ready to process the data
-pub type StepStartFn = extern fn(module_types::ModuleHandle) -> module_types::StepStartFnResult;
+pub type StepStartFn = extern "C" fn(module_types::ModuleHandle) -> module_types::StepStartFnResult;
 /// Sets a param for module step. Typicaly param is passed from step definition
-pub type StepSetParamFn = extern fn(module_types::ModuleHandle, std_types::ConstCharPtr, std_types::ConstCharPtr);
+pub type StepSetParamFn = extern "C" fn(module_types::ModuleHandle, std_types::ConstCharPtr, std_types::ConstCharPtr) -> module_types::StepSetParamFnResult;
+/// Returns the poison status of module step
+pub type StepGetPoisonStatusFn = extern "C" fn(module_types::ModuleHandle) -> module_types::StepPoisonStatus;
+/// Changes the log level of step at runtime
+pub type StepSetLogLevelFn = extern "C" fn(module_types::ModuleHandle, LogLevel);
 /// Signals the module step to shut down
-pub type ModuleStepShutdownFn = extern fn(module_types::ModuleHandle);
+pub type ModuleStepShutdownFn = extern "C" fn(module_types::ModuleHandle);
+/// Stops accepting records until the step is resumed. The process record function returns the 'busy' status meanwhile
+pub type StepPauseFn = extern "C" fn(module_types::ModuleHandle) -> module_types::StepControlFnResult;
+/// Resumes a paused step
+pub type StepResumeFn = extern "C" fn(module_types::ModuleHandle) -> module_types::StepControlFnResult;
+/// Stops accepting records and processes the buffered ones. Returns immediately;
+/// the termination callback is called when draining is finished or the timeout (ms) expires. Zero timeout means no timeout
+pub type StepDrainFn = extern "C" fn(module_types::ModuleHandle, std_types::Uint) -> module_types::StepControlFnResult;
 
 // These are callback functions
 
 /// A callback for received data processed by main app. Arguments are:
 /// 1. Step handle to identity the source
 /// 2. A record: payload + metadata
-pub type ModuleOnDataReceiveCb = extern fn(module_types::ModuleHandle, module_types::Record);
-pub type ModuleTerminationHandlerFn = extern fn(std_types::Uint);
+pub type ModuleOnDataReceiveCb = extern "C" fn(module_types::ModuleHandle, module_types::Record);
+/// Same as ModuleOnDataReceiveCb, but passes several records at once.
+/// Main app takes ownership of records; the array itself is owned by caller
+pub type ModuleOnDataReceiveBatchCb = extern "C" fn(module_types::ModuleHandle, Array<module_types::Record>);
+/// A callback for the delivery outcome of record. Arguments are:
+/// 1. Handle of step which reports the outcome (usually a destination)
+/// 2. Identifier of record
+/// 3. Delivery outcome
+pub type ModuleOnRecordAckCb = extern "C" fn(module_types::ModuleHandle, module_types::RecordId, module_types::RecordAckStatus);
+/// A callback for records which failed processing. Arguments are:
+/// 1. Handle of step which failed to process the record
+/// 2. A record annotated with error metadata (see `dead_letter` module). Main app takes ownership of record
+pub type ModuleOnDeadLetterCb = extern "C" fn(module_types::ModuleHandle, module_types::Record);
+/// A callback for log records of module
+pub type ModuleLogCb = extern "C" fn(LogRecord);
+/// A callback for step termination. Arguments are the step handle and how the step terminated
+pub type ModuleTerminationHandlerFn = extern "C" fn(module_types::ModuleHandle, module_types::StepTerminationStatus);
 
 // These functions are called from host app
 
-pub type ModuleFreeRecordFn = extern fn(module_types::Record);
-pub type ModuleFreeCharPtrFn = extern fn(std_types::ConstCharPtr);
\ No newline at end of file
+pub type ModuleFreeRecordFn = extern "C" fn(module_types::Record);
+pub type ModuleFreeCharPtrFn = extern "C" fn(std_types::ConstCharPtr);
\ No newline at end of file
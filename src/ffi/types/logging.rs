@@ -0,0 +1,60 @@
+use crate::ffi::types::{module::ModuleHandle, std_types};
+
+/// A log level. Also used as a filter, where `Off` disables logging
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(value: log::Level) -> Self {
+        match value {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl From<log::LevelFilter> for LogLevel {
+    fn from(value: log::LevelFilter) -> Self {
+        match value.to_level() {
+            Some(l) => l.into(),
+            None => LogLevel::Off,
+        }
+    }
+}
+
+/// A log message passed to host. Strings are owned by module and valid only during the callback
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// A step which produced the message. Not applicable if `has_step` is false
+    pub step: ModuleHandle,
+    pub has_step: bool,
+    /// Usually a Rust module path, e.g. 'torustiq_kafka::consumer'
+    pub target: std_types::ConstCharPtr,
+    pub message: std_types::ConstCharPtr,
+}
@@ -0,0 +1,196 @@
+use std::{ffi::{CStr, CString}, fmt, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::ffi::{
+    types::{buffer::ByteBuffer, collections::Array, std_types},
+    utils::strings::{cchar_const_deallocate, string_to_cchar},
+};
+
+/// A typed value of record metadata
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum RecordMetadataValue {
+    Null,
+    Int64(i64),
+    Float64(f64),
+    Bool(bool),
+    /// Arbitrary bytes, e.g. binary message headers
+    Bytes(ByteBuffer),
+    /// A UTF-8 string. Unlike C-strings, it may contain NUL characters
+    String(ByteBuffer),
+    /// Microseconds since Unix epoch
+    Timestamp(i64),
+}
+
+impl RecordMetadataValue {
+    pub fn free_contents(&mut self) {
+        match self {
+            RecordMetadataValue::Bytes(b) | RecordMetadataValue::String(b) => b.free_contents(),
+            _ => {},
+        }
+    }
+}
+
+/// A single item of typed metadata: a key and a typed value
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TypedRecordMetadata {
+    pub name: std_types::ConstCharPtr,
+    pub value: RecordMetadataValue,
+}
+
+impl TypedRecordMetadata {
+    pub fn free_contents(&mut self) {
+        cchar_const_deallocate(self.name);
+        self.value.free_contents();
+    }
+
+    /// Creates an array which releases both the items and their contents when freed
+    pub fn array_from_vec(items: Vec<TypedRecordMetadata>) -> Array<TypedRecordMetadata> {
+        Array::from_vec_with_free_fn(items, free_typed_metadata_array)
+    }
+
+    /// Makes a copy of key and value allocated by this library
+    pub(crate) fn copy(&self) -> TypedRecordMetadata {
+        TypedRecordMetadata {
+            name: CString::from(unsafe { CStr::from_ptr(self.name) }).into_raw(),
+            value: MetadataValue::from(&self.value).into(),
+        }
+    }
+}
+
+/// Releases an array of typed metadata allocated by this library
+extern "C" fn free_typed_metadata_array(data: *mut TypedRecordMetadata, len: std_types::Uint) {
+    let s = std::ptr::slice_from_raw_parts_mut(data, len as usize);
+    let mut items = unsafe { Box::from_raw(s) };
+    items.iter_mut().for_each(|item| item.free_contents());
+}
+
+impl<S: Into<String>> From<(S, MetadataValue)> for TypedRecordMetadata {
+    fn from(value: (S, MetadataValue)) -> Self {
+        TypedRecordMetadata {
+            name: string_to_cchar(value.0),
+            value: value.1.into(),
+        }
+    }
+}
+
+/// An owned Rust representation of metadata value
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetadataValue {
+    Null,
+    Int64(i64),
+    Float64(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    String(String),
+    Timestamp(SystemTime),
+}
+
+/// A string representation of value. Used by the string-only metadata API.
+/// Bytes are converted lossily; timestamps are printed as microseconds since Unix epoch
+/// ```
+/// use torustiq_common::ffi::types::metadata::MetadataValue;
+/// assert_eq!(MetadataValue::Int64(-5).to_string(), "-5");
+/// assert_eq!(MetadataValue::Bool(true).to_string(), "true");
+/// assert_eq!(MetadataValue::Null.to_string(), "");
+/// ```
+impl fmt::Display for MetadataValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataValue::Null => Ok(()),
+            MetadataValue::Int64(v) => write!(f, "{}", v),
+            MetadataValue::Float64(v) => write!(f, "{}", v),
+            MetadataValue::Bool(v) => write!(f, "{}", v),
+            MetadataValue::Bytes(v) => write!(f, "{}", String::from_utf8_lossy(v)),
+            MetadataValue::String(v) => write!(f, "{}", v),
+            MetadataValue::Timestamp(v) => write!(f, "{}", system_time_to_micros(*v)),
+        }
+    }
+}
+
+fn system_time_to_micros(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+fn micros_to_system_time(micros: i64) -> SystemTime {
+    let d = Duration::from_micros(micros.unsigned_abs());
+    if micros >= 0 {
+        UNIX_EPOCH + d
+    } else {
+        UNIX_EPOCH - d
+    }
+}
+
+impl From<&RecordMetadataValue> for MetadataValue {
+    fn from(value: &RecordMetadataValue) -> Self {
+        match value {
+            RecordMetadataValue::Null => MetadataValue::Null,
+            RecordMetadataValue::Int64(v) => MetadataValue::Int64(*v),
+            RecordMetadataValue::Float64(v) => MetadataValue::Float64(*v),
+            RecordMetadataValue::Bool(v) => MetadataValue::Bool(*v),
+            RecordMetadataValue::Bytes(v) => MetadataValue::Bytes(v.to_byte_vec()),
+            RecordMetadataValue::String(v) => MetadataValue::String(v.to_string()),
+            RecordMetadataValue::Timestamp(v) => MetadataValue::Timestamp(micros_to_system_time(*v)),
+        }
+    }
+}
+
+/// NB: the output must be deallocated later using 'free_contents'
+impl From<MetadataValue> for RecordMetadataValue {
+    fn from(value: MetadataValue) -> Self {
+        match value {
+            MetadataValue::Null => RecordMetadataValue::Null,
+            MetadataValue::Int64(v) => RecordMetadataValue::Int64(v),
+            MetadataValue::Float64(v) => RecordMetadataValue::Float64(v),
+            MetadataValue::Bool(v) => RecordMetadataValue::Bool(v),
+            MetadataValue::Bytes(v) => RecordMetadataValue::Bytes(ByteBuffer::from(v)),
+            MetadataValue::String(v) => RecordMetadataValue::String(ByteBuffer::from(v)),
+            MetadataValue::Timestamp(v) => RecordMetadataValue::Timestamp(system_time_to_micros(v)),
+        }
+    }
+}
+
+impl From<i64> for MetadataValue {
+    fn from(value: i64) -> Self {
+        MetadataValue::Int64(value)
+    }
+}
+
+impl From<f64> for MetadataValue {
+    fn from(value: f64) -> Self {
+        MetadataValue::Float64(value)
+    }
+}
+
+impl From<bool> for MetadataValue {
+    fn from(value: bool) -> Self {
+        MetadataValue::Bool(value)
+    }
+}
+
+impl From<Vec<u8>> for MetadataValue {
+    fn from(value: Vec<u8>) -> Self {
+        MetadataValue::Bytes(value)
+    }
+}
+
+impl From<String> for MetadataValue {
+    fn from(value: String) -> Self {
+        MetadataValue::String(value)
+    }
+}
+
+impl From<&str> for MetadataValue {
+    fn from(value: &str) -> Self {
+        MetadataValue::String(value.to_string())
+    }
+}
+
+impl From<SystemTime> for MetadataValue {
+    fn from(value: SystemTime) -> Self {
+        MetadataValue::Timestamp(value)
+    }
+}
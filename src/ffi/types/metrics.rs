@@ -0,0 +1,104 @@
+use crate::{
+    ffi::{
+        types::{collections::Array, module::ModuleHandle, std_types},
+        utils::strings::{cchar_const_deallocate, cchar_to_string, string_to_cchar},
+    },
+    metrics::{MetricKind, MetricSnapshot, MetricValue},
+};
+
+/// A bucket of histogram
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HistogramBucket {
+    /// Upper bound of the bucket, inclusive
+    pub le: f64,
+    /// Number of observed values which are less or equal to the upper bound
+    pub count: u64,
+}
+
+/// A value of metric passed across the C ABI
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MetricSample {
+    pub step: ModuleHandle,
+    pub name: std_types::ConstCharPtr,
+    pub help: std_types::ConstCharPtr,
+    pub kind: MetricKind,
+    /// A value of counter or gauge, or a sum of observed values for histogram
+    pub value: f64,
+    /// Number of observed values. Histograms only
+    pub count: u64,
+    /// Cumulative buckets without the implicit '+Inf' one. Histograms only
+    pub buckets: Array<HistogramBucket>,
+}
+
+impl MetricSample {
+    pub fn free_contents(&mut self) {
+        cchar_const_deallocate(self.name);
+        cchar_const_deallocate(self.help);
+        self.buckets.free_contents();
+    }
+}
+
+/// Releases an array of samples allocated by this library
+extern "C" fn free_metric_sample_array(data: *mut MetricSample, len: std_types::Uint) {
+    let s = std::ptr::slice_from_raw_parts_mut(data, len as usize);
+    let mut items = unsafe { Box::from_raw(s) };
+    items.iter_mut().for_each(|item| item.free_contents());
+}
+
+/// NB: the output must be deallocated later using 'free_contents'
+impl From<&MetricSnapshot> for MetricSample {
+    fn from(value: &MetricSnapshot) -> Self {
+        let (v, count, buckets) = match &value.value {
+            MetricValue::Counter(c) => (*c as f64, 0, Vec::new()),
+            MetricValue::Gauge(g) => (*g, 0, Vec::new()),
+            MetricValue::Histogram { buckets, sum, count } => (*sum, *count,
+                buckets.iter().map(|(le, count)| HistogramBucket { le: *le, count: *count }).collect()),
+        };
+        MetricSample {
+            step: value.step,
+            name: string_to_cchar(value.name.as_str()),
+            help: string_to_cchar(value.help.as_str()),
+            kind: value.value.kind(),
+            value: v,
+            count,
+            buckets: Array::from_vec(buckets),
+        }
+    }
+}
+
+impl From<&MetricSample> for MetricSnapshot {
+    fn from(value: &MetricSample) -> Self {
+        MetricSnapshot {
+            step: value.step,
+            name: cchar_to_string(value.name),
+            help: cchar_to_string(value.help),
+            value: match value.kind {
+                MetricKind::Counter => MetricValue::Counter(value.value as u64),
+                MetricKind::Gauge => MetricValue::Gauge(value.value),
+                MetricKind::Histogram => MetricValue::Histogram {
+                    buckets: value.buckets.as_slice().iter().map(|b| (b.le, b.count)).collect(),
+                    sum: value.value,
+                    count: value.count,
+                },
+            },
+        }
+    }
+}
+
+/// Converts a snapshot for passing across the C ABI.
+/// NB: the output must be deallocated later using 'free_contents'
+/// ```
+/// use torustiq_common::ffi::types::metrics::metrics_to_array;
+/// use torustiq_common::metrics::{counter, snapshot, MetricSnapshot};
+/// counter(13, "errors_total", "Errors").inc();
+/// let metrics = snapshot(Some(13));
+/// let mut arr = metrics_to_array(&metrics);
+/// let restored: Vec<MetricSnapshot> = arr.as_slice().iter().map(MetricSnapshot::from).collect();
+/// assert_eq!(restored, metrics);
+/// arr.free_contents();
+/// ```
+pub fn metrics_to_array(metrics: &[MetricSnapshot]) -> Array<MetricSample> {
+    Array::from_vec_with_free_fn(metrics.iter().map(MetricSample::from).collect(), free_metric_sample_array)
+}
@@ -1,5 +1,11 @@
 pub mod buffer;
+pub mod capabilities;
 pub mod collections;
+pub mod error;
 pub mod functions;
+pub mod logging;
+pub mod metadata;
+pub mod metrics;
 pub mod module;
+pub mod params;
 pub mod std_types;
\ No newline at end of file
@@ -1,14 +1,21 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ffi::{CStr, CString}};
 
 use crate::ffi::{
     types::std_types,
-    utils::strings::{cchar_to_string, string_to_cchar}
+    utils::strings::{cchar_const_deallocate, cchar_to_string, string_to_cchar}
 };
 
-use super::{buffer::ByteBuffer, collections::Array};
+use super::{
+    buffer::ByteBuffer,
+    capabilities::{ApiCapabilities, NegotiatedCapabilities},
+    collections::Array,
+    error::ModuleError,
+    logging::LogLevel,
+    metadata::{MetadataValue, TypedRecordMetadata},
+};
 use crate::ffi::types::functions as fn_defs;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 #[repr(C)]
 pub enum ModuleKind {
     /// A pipeline module. Extracts, transforms, loads the data.
@@ -44,7 +51,25 @@ pub enum PipelineModuleKind {
 #[repr(C)]
 #[derive(Clone)]
 pub struct LibCommonInitArgs {
+    /// API versions and optional features supported by host
+    pub host_capabilities: ApiCapabilities,
     pub on_step_terminate_cb: fn_defs::ModuleTerminationHandlerFn,
+    /// Receives log records of module. If not provided, module logs to stderr
+    pub log_cb: Option<fn_defs::ModuleLogCb>,
+    /// The default log level. Levels of individual steps can be changed later
+    pub log_level: LogLevel,
+}
+
+/// Returns the status of library initialization
+#[repr(C)]
+pub enum LibInitFnResult {
+    /// Initialization succeeded. Argument is the API version and features enabled on both sides
+    Ok(NegotiatedCapabilities),
+    /// Host and module have no API version in common.
+    /// Argument is the capabilities of module, so host can report or pick another module build
+    ErrorIncompatibleApiVersion(ApiCapabilities),
+    /// Other kind of error occurred. More details in error object
+    ErrorMisc(ModuleError),
 }
 
 /// Arguments passed to initialization function of pipeline library
@@ -53,6 +78,12 @@ pub struct LibCommonInitArgs {
 pub struct LibPipelineInitArgs {
     pub common: LibCommonInitArgs,
     pub on_data_receive_cb: fn_defs::ModuleOnDataReceiveCb,
+    /// Receives several records in a single call. Optional; used if batching is negotiated with host
+    pub on_data_receive_batch_cb: Option<fn_defs::ModuleOnDataReceiveBatchCb>,
+    /// Reports the delivery outcome of tracked record. Optional; used if acknowledgements are negotiated with host
+    pub on_record_ack_cb: Option<fn_defs::ModuleOnRecordAckCb>,
+    /// Receives records which failed processing. Optional; used if dead letters are negotiated with host
+    pub on_dead_letter_cb: Option<fn_defs::ModuleOnDeadLetterCb>,
 }
 
 /// Arguments passed to initialization function of listener library
@@ -86,6 +117,34 @@ pub struct RecordMetadata {
     pub value: std_types::ConstCharPtr,
 }
 
+impl RecordMetadata {
+    pub fn free_contents(&mut self) {
+        cchar_const_deallocate(self.name);
+        cchar_const_deallocate(self.value);
+    }
+
+    /// Creates an array which releases both the items and their strings when freed
+    pub fn array_from_vec(items: Vec<RecordMetadata>) -> Array<RecordMetadata> {
+        Array::from_vec_with_free_fn(items, free_record_metadata_array)
+    }
+
+    /// Makes a copy of key and value allocated by this library
+    fn copy(&self) -> RecordMetadata {
+        let (name, value) = unsafe { (CStr::from_ptr(self.name), CStr::from_ptr(self.value)) };
+        RecordMetadata {
+            name: CString::from(name).into_raw(),
+            value: CString::from(value).into_raw(),
+        }
+    }
+}
+
+/// Releases an array of string metadata allocated by this library
+extern "C" fn free_record_metadata_array(data: *mut RecordMetadata, len: std_types::Uint) {
+    let s = std::ptr::slice_from_raw_parts_mut(data, len as usize);
+    let mut items = unsafe { Box::from_raw(s) };
+    items.iter_mut().for_each(|item| item.free_contents());
+}
+
 impl From<(String, String)> for RecordMetadata {
     fn from(value: (String, String)) -> Self {
         RecordMetadata {
@@ -95,12 +154,44 @@ impl From<(String, String)> for RecordMetadata {
     }
 }
 
-/// A single piece of data to transmit. Contains the data itself + metadata
+/// An identifier of record assigned by the source step. Used to acknowledge the delivery
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RecordId {
+    /// A handle of step which produced the record
+    pub source: ModuleHandle,
+    /// A sequence number within source step. Zero means the record is not tracked
+    pub seq: u64,
+}
+
+impl RecordId {
+    /// An identifier of records which don't need an acknowledgement
+    pub const UNTRACKED: RecordId = RecordId { source: 0, seq: 0 };
+
+    pub fn is_tracked(&self) -> bool {
+        self.seq != 0
+    }
+}
+
+/// An outcome of record delivery reported by destination step
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordAckStatus {
+    /// The record is durably written
+    Ack,
+    /// The record cannot be written; source should deliver it again
+    Nack,
+}
+
+/// A single piece of data to transmit. Contains the data itself + metadata.
+/// Metadata is split into string-only and typed items; keys should not repeat across them
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Record {
+    pub id: RecordId,
     pub content: ByteBuffer,
     pub metadata: Array<RecordMetadata>,
+    pub typed_metadata: Array<TypedRecordMetadata>,
 }
 
 unsafe impl Send for Record {}
@@ -113,30 +204,250 @@ impl Record {
             .into_iter()
             .map(|kv| kv.into()).collect();
         Record {
+            id: RecordId::UNTRACKED,
             content: ByteBuffer::from(content),
-            metadata: Array::from_vec(metadata_vec),
+            metadata: RecordMetadata::array_from_vec(metadata_vec),
+            typed_metadata: TypedRecordMetadata::array_from_vec(Vec::new()),
         }
     }
 
-    /// Returns metadata as hashmap of string key-value pairs
+    /// Creates a record from content (vector of bytes) and typed metadata
+    /// ```
+    /// use std::{collections::HashMap, ffi::{CStr, CString}};
+    /// use torustiq_common::ffi::types::{metadata::MetadataValue, module::Record};
+    /// let metadata = HashMap::from([
+    ///     ("offset".to_string(), MetadataValue::Int64(42)),
+    ///     ("header".to_string(), MetadataValue::Bytes(vec![0, 1, 0])),
+    /// ]);
+    /// let mut r = Record::from_typed_metadata(vec![], metadata.clone());
+    /// assert_eq!(r.get_typed_metadata(), metadata);
+    /// assert_eq!(r.get_metadata_as_hashmap().get("offset"), Some(&"42".to_string()));
+    /// r.free_contents();
+    /// ```
+    pub fn from_typed_metadata(content: Vec<u8>, metadata: HashMap<String, MetadataValue>) -> Self {
+        let metadata_vec: Vec<TypedRecordMetadata> = metadata
+            .into_iter()
+            .map(|kv| kv.into()).collect();
+        Record {
+            id: RecordId::UNTRACKED,
+            content: ByteBuffer::from(content),
+            metadata: RecordMetadata::array_from_vec(Vec::new()),
+            typed_metadata: TypedRecordMetadata::array_from_vec(metadata_vec),
+        }
+    }
+
+    /// Returns metadata as hashmap of string key-value pairs.
+    /// Typed values are converted to strings
     pub fn get_metadata_as_hashmap(&self) -> HashMap<String, String> {
-        let mtd_len = self.metadata.len as usize;
-        let metadata: Vec<RecordMetadata> = unsafe { Vec::from_raw_parts(self.metadata.data, mtd_len, mtd_len) };
-        metadata.into_iter()
+        let mut result = self.metadata.as_slice().iter()
             .map(|record| (cchar_to_string(record.name), cchar_to_string(record.value)))
-            .collect::<HashMap<String, String>>()
+            .collect::<HashMap<String, String>>();
+        for item in self.typed_metadata.as_slice() {
+            result.insert(cchar_to_string(item.name), MetadataValue::from(&item.value).to_string());
+        }
+        result
+    }
+
+    /// Returns metadata as hashmap of typed values. String-only items are returned as string values
+    pub fn get_typed_metadata(&self) -> HashMap<String, MetadataValue> {
+        let mut result: HashMap<String, MetadataValue> = self.metadata.as_slice().iter()
+            .map(|item| (cchar_to_string(item.name), MetadataValue::String(cchar_to_string(item.value))))
+            .collect();
+        for item in self.typed_metadata.as_slice() {
+            result.insert(cchar_to_string(item.name), MetadataValue::from(&item.value));
+        }
+        result
+    }
+
+    /// Iterates over string metadata without copying or taking ownership
+    pub fn metadata_iter(&self) -> impl Iterator<Item = (&CStr, &CStr)> {
+        self.metadata.as_slice().iter()
+            .map(|item| unsafe { (CStr::from_ptr(item.name), CStr::from_ptr(item.value)) })
+    }
+
+    /// Returns a value of string metadata item
+    /// ```
+    /// use std::collections::HashMap;
+    /// use torustiq_common::ffi::types::module::Record;
+    /// let mut r = Record::from_std_types(vec![], HashMap::from([("a".to_string(), "1".to_string())]));
+    /// r.set_metadata("b", "2");
+    /// r.set_metadata("a", "3");
+    /// assert_eq!(r.get_metadata("a").unwrap().to_str(), Ok("3"));
+    /// assert!(r.remove_metadata("b"));
+    /// assert!(!r.contains_metadata("b"));
+    /// assert_eq!(r.metadata_iter().count(), 1);
+    /// r.free_contents();
+    /// ```
+    pub fn get_metadata(&self, key: &str) -> Option<&CStr> {
+        self.metadata_iter()
+            .find(|(k, _)| k.to_bytes() == key.as_bytes())
+            .map(|(_, v)| v)
+    }
+
+    /// Returns a value of metadata item. String-only items are returned as string values
+    pub fn get_typed_metadata_value(&self, key: &str) -> Option<MetadataValue> {
+        self.typed_metadata.as_slice().iter()
+            .find(|item| cname_eq(item.name, key))
+            .map(|item| MetadataValue::from(&item.value))
+            .or_else(|| self.get_metadata(key).map(|v| MetadataValue::String(v.to_string_lossy().to_string())))
+    }
+
+    /// Checks if either string or typed metadata contains the key
+    pub fn contains_metadata(&self, key: &str) -> bool {
+        self.metadata.as_slice().iter().any(|item| cname_eq(item.name, key))
+            || self.typed_metadata.as_slice().iter().any(|item| cname_eq(item.name, key))
+    }
+
+    /// Sets a string metadata item. Replaces the existing string or typed item with the same key
+    pub fn set_metadata<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key: String = key.into();
+        self.remove_typed_metadata(&key);
+        let mut items = self.copy_string_metadata(Some(&key));
+        items.push((key, value.into()).into());
+        self.replace_string_metadata(items);
+    }
+
+    /// Sets a typed metadata item. Replaces the existing string or typed item with the same key
+    pub fn set_typed_metadata<K: Into<String>>(&mut self, key: K, value: MetadataValue) {
+        let key: String = key.into();
+        self.remove_string_metadata(&key);
+        let mut items = self.copy_typed_metadata(Some(&key));
+        items.push((key, value).into());
+        self.replace_typed_metadata(items);
+    }
+
+    /// Converts typed metadata items into string ones, e.g. for a peer which doesn't support typed metadata
+    /// ```
+    /// use std::collections::HashMap;
+    /// use torustiq_common::ffi::types::{metadata::MetadataValue, module::Record};
+    /// let mut r = Record::from_std_types(vec![], HashMap::new());
+    /// r.set_typed_metadata("attempt", MetadataValue::Int64(2));
+    /// r.flatten_typed_metadata();
+    /// assert_eq!(r.get_metadata("attempt").unwrap().to_str(), Ok("2"));
+    /// assert!(r.typed_metadata.as_slice().is_empty());
+    /// r.free_contents();
+    /// ```
+    pub fn flatten_typed_metadata(&mut self) {
+        if self.typed_metadata.as_slice().is_empty() {
+            return;
+        }
+        let mut items = self.copy_string_metadata(None);
+        items.extend(self.typed_metadata.as_slice().iter()
+            .map(|item| RecordMetadata::from((cchar_to_string(item.name), MetadataValue::from(&item.value).to_string()))));
+        self.replace_string_metadata(items);
+        self.replace_typed_metadata(Vec::new());
+    }
+
+    /// Removes a metadata item by key. Returns true if the item existed
+    pub fn remove_metadata(&mut self, key: &str) -> bool {
+        let removed_string = self.remove_string_metadata(key);
+        let removed_typed = self.remove_typed_metadata(key);
+        removed_string || removed_typed
+    }
+
+    fn remove_string_metadata(&mut self, key: &str) -> bool {
+        if !self.metadata.as_slice().iter().any(|item| cname_eq(item.name, key)) {
+            return false;
+        }
+        let items = self.copy_string_metadata(Some(key));
+        self.replace_string_metadata(items);
+        true
+    }
+
+    fn remove_typed_metadata(&mut self, key: &str) -> bool {
+        if !self.typed_metadata.as_slice().iter().any(|item| cname_eq(item.name, key)) {
+            return false;
+        }
+        let items = self.copy_typed_metadata(Some(key));
+        self.replace_typed_metadata(items);
+        true
+    }
+
+    // Metadata arrays might be allocated by another library, so they are never modified in place.
+    // Instead, the items are copied using the allocator of this library and the old array is released
+    // with its own free function
+
+    /// Copies string metadata items except the one with provided key
+    fn copy_string_metadata(&self, except_key: Option<&str>) -> Vec<RecordMetadata> {
+        self.metadata.as_slice().iter()
+            .filter(|item| except_key.is_none_or(|k| !cname_eq(item.name, k)))
+            .map(|item| item.copy())
+            .collect()
+    }
+
+    /// Copies typed metadata items except the one with provided key
+    fn copy_typed_metadata(&self, except_key: Option<&str>) -> Vec<TypedRecordMetadata> {
+        self.typed_metadata.as_slice().iter()
+            .filter(|item| except_key.is_none_or(|k| !cname_eq(item.name, k)))
+            .map(|item| item.copy())
+            .collect()
+    }
+
+    fn replace_string_metadata(&mut self, items: Vec<RecordMetadata>) {
+        self.metadata.free_contents();
+        self.metadata = RecordMetadata::array_from_vec(items);
+    }
+
+    fn replace_typed_metadata(&mut self, items: Vec<TypedRecordMetadata>) {
+        self.typed_metadata.free_contents();
+        self.typed_metadata = TypedRecordMetadata::array_from_vec(items);
     }
 
     pub fn get_content_len(&self) -> usize {
         self.content.len
     }
 
+    /// Makes a copy of content and all metadata using the allocator of this library,
+    /// so both records can be freed independently
+    pub fn deep_copy(&self) -> Record {
+        Record {
+            id: self.id,
+            content: ByteBuffer::from(self.content.to_byte_vec()),
+            metadata: RecordMetadata::array_from_vec(self.copy_string_metadata(None)),
+            typed_metadata: TypedRecordMetadata::array_from_vec(self.copy_typed_metadata(None)),
+        }
+    }
+
+    /// Makes a copy of record for another consumer, e.g. when a record is sent to several steps.
+    /// Shared content is referenced without copying; metadata is always copied
+    /// ```
+    /// use std::collections::HashMap;
+    /// use torustiq_common::ffi::types::module::Record;
+    /// let mut src = Record::from_std_types(b"payload".to_vec(), HashMap::new());
+    /// src.make_content_shared();
+    /// let mut branch = src.share();
+    /// src.free_contents();
+    /// assert_eq!(branch.content.to_string(), "payload");
+    /// branch.free_contents();
+    /// ```
+    pub fn share(&self) -> Record {
+        Record {
+            id: self.id,
+            content: self.content.share(),
+            metadata: RecordMetadata::array_from_vec(self.copy_string_metadata(None)),
+            typed_metadata: TypedRecordMetadata::array_from_vec(self.copy_typed_metadata(None)),
+        }
+    }
+
+    /// Moves content into a shared buffer, so `share` doesn't copy it. The bytes are copied at most once
+    pub fn make_content_shared(&mut self) {
+        self.content = self.content.into_shared();
+    }
+
+    /// Deallocates content and metadata using their own free functions,
+    /// so a record can be freed by any library. Calling this function again has no effect
     pub fn free_contents(&mut self) {
         self.content.free_contents();
         self.metadata.free_contents();
+        self.typed_metadata.free_contents();
     }
 }
 
+/// Compares a metadata key stored as C-string with a Rust string
+fn cname_eq(name: std_types::ConstCharPtr, key: &str) -> bool {
+    unsafe { CStr::from_ptr(name) }.to_bytes() == key.as_bytes()
+}
+
 pub type ModuleHandle = std_types::Uint;
 
 /// Returns the status of listener module configuration
@@ -144,8 +455,8 @@ pub type ModuleHandle = std_types::Uint;
 pub enum ModuleListenerConfigureFnResult {
     /// Configuration succeeded
     Ok,
-    /// Other kind of error occurred. More details in text message
-    ErrorMisc(std_types::ConstCharPtr),
+    /// Other kind of error occurred. More details in error object
+    ErrorMisc(ModuleError),
 }
 
 /// Returns the status of pipeline module configuration
@@ -160,8 +471,18 @@ pub enum ModulePipelineConfigureFnResult {
     /// Some modules can have issues with having initialized for multiple steps
     /// Argument is a handle of previously initialized module which caused a conflict
     ErrorMultipleStepsNotSupported(ModuleHandle),
-    /// Other kind of error occurred. More details in text message
-    ErrorMisc(std_types::ConstCharPtr),
+    /// Other kind of error occurred. More details in error object
+    ErrorMisc(ModuleError),
+}
+
+/// Reports whether the step is still operational
+#[repr(C)]
+pub enum StepPoisonStatus {
+    /// No panic occurred in step
+    Healthy,
+    /// A panic occurred while step was processing a call. Step rejects further records.
+    /// Argument describes the panic
+    Poisoned(std_types::ConstCharPtr),
 }
 
 /// Returns the status of module step start
@@ -169,8 +490,8 @@ pub enum ModulePipelineConfigureFnResult {
 pub enum StepStartFnResult {
     /// Started successfully
     Ok,
-    /// Other kind of error occurred. More details in text message
-    ErrorMisc(std_types::ConstCharPtr),
+    /// Other kind of error occurred. More details in error object
+    ErrorMisc(ModuleError),
 }
 
 /// A result of sending a record to further processing
@@ -181,8 +502,56 @@ pub enum StepStartFnResult {
 pub enum ModulePipelineProcessRecordFnResult {
     /// Processing succeeded. No immediate error occurred
     Ok(bool),
-    ///
+    /// No module step is registered under the provided handle
     ErrWrongModuleHandle(ModuleHandle, bool),
     /// Cannot proces record due to error
-    ErrMisc(std_types::ConstCharPtr, bool),
+    ErrMisc(ModuleError, bool),
+    /// The step queue is full. Record is not consumed; host should retry later
+    /// and slow down the upstream steps
+    ErrBusy(ModuleHandle, bool),
+}
+
+/// A result of setting a step param
+#[repr(C)]
+pub enum StepSetParamFnResult {
+    Ok,
+    /// The param is unknown or its value is invalid
+    ErrorMisc(ModuleError),
+}
+
+/// A result of pause, resume and drain functions
+#[repr(C)]
+pub enum StepControlFnResult {
+    Ok,
+    /// The operation is not possible in the current state of step, e.g. resuming a draining step
+    ErrorMisc(ModuleError),
+}
+
+/// Describes how the step terminated. Passed to host in the termination callback
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepTerminationStatus {
+    /// The step was shut down. Records buffered inside step might be lost
+    Shutdown,
+    /// The step was drained: all accepted records were processed
+    Drained,
+    /// The step didn't finish draining in time. Some of accepted records might be lost
+    DrainTimeout,
+    /// The step terminated because of error, e.g. it's poisoned
+    Failed,
+}
+
+/// A result of processing a batch of records. Each variant contains the number of records
+/// consumed from the beginning of batch. Ownership of consumed records is passed to module;
+/// the rest of records are still owned by caller. The array itself is always owned by caller
+#[repr(C)]
+pub enum ModulePipelineProcessRecordsFnResult {
+    /// Processing succeeded. No immediate error occurred
+    Ok(std_types::Uint),
+    /// No module step is registered under the provided handle
+    ErrWrongModuleHandle(ModuleHandle, std_types::Uint),
+    /// The step queue is full. Host should retry the rest of batch later
+    ErrBusy(ModuleHandle, std_types::Uint),
+    /// Cannot proces records due to error
+    ErrMisc(ModuleError, std_types::Uint),
 }
\ No newline at end of file
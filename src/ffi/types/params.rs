@@ -0,0 +1,132 @@
+use crate::{
+    ffi::{
+        types::{collections::Array, std_types},
+        utils::strings::{cchar_const_deallocate, cchar_to_string, string_to_cchar},
+    },
+    params::{ParamSchema, ParamSpec, ParamType},
+    secret::REDACTED,
+};
+
+/// A description of step param passed across the C ABI
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ParamDescriptor {
+    pub name: std_types::ConstCharPtr,
+    pub param_type: ParamType,
+    pub required: bool,
+    /// A value used if param is not set. Null if there is no default value
+    pub default_value: std_types::ConstCharPtr,
+    pub description: std_types::ConstCharPtr,
+    /// The param contains credentials and must not be logged
+    pub secret: bool,
+}
+
+impl ParamDescriptor {
+    pub fn free_contents(&mut self) {
+        cchar_const_deallocate(self.name);
+        if !self.default_value.is_null() {
+            cchar_const_deallocate(self.default_value);
+        }
+        cchar_const_deallocate(self.description);
+    }
+}
+
+/// Params accepted by module
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LibParamSchema {
+    pub params: Array<ParamDescriptor>,
+    /// Params which are not described are accepted too. Always true if module doesn't provide a schema
+    pub allow_unknown: bool,
+}
+
+impl LibParamSchema {
+    pub fn free_contents(&mut self) {
+        self.params.free_contents();
+    }
+}
+
+/// Releases an array of descriptors allocated by this library
+extern "C" fn free_param_descriptor_array(data: *mut ParamDescriptor, len: std_types::Uint) {
+    let s = std::ptr::slice_from_raw_parts_mut(data, len as usize);
+    let mut items = unsafe { Box::from_raw(s) };
+    items.iter_mut().for_each(|item| item.free_contents());
+}
+
+/// NB: the output must be deallocated later using 'free_contents'
+impl From<&ParamSpec> for ParamDescriptor {
+    fn from(value: &ParamSpec) -> Self {
+        ParamDescriptor {
+            name: string_to_cchar(value.name.as_str()),
+            param_type: value.param_type,
+            required: value.required,
+            // Default values of secrets are not exposed to host
+            default_value: match &value.default {
+                Some(_) if value.is_secret() => string_to_cchar(REDACTED),
+                Some(d) => string_to_cchar(d.as_str()),
+                None => std::ptr::null(),
+            },
+            description: string_to_cchar(value.description.as_str()),
+            secret: value.is_secret(),
+        }
+    }
+}
+
+impl From<&ParamDescriptor> for ParamSpec {
+    fn from(value: &ParamDescriptor) -> Self {
+        ParamSpec {
+            name: cchar_to_string(value.name),
+            param_type: value.param_type,
+            required: value.required,
+            default: match value.default_value.is_null() {
+                true => None,
+                false => Some(cchar_to_string(value.default_value)),
+            },
+            description: cchar_to_string(value.description),
+            secret: value.secret,
+        }
+    }
+}
+
+/// Converts a schema for passing across the C ABI. No schema means any param is accepted.
+/// NB: the output must be deallocated later using 'free_contents'
+/// ```
+/// use torustiq_common::ffi::types::params::LibParamSchema;
+/// use torustiq_common::params::{ParamSchema, ParamSpec, ParamType};
+/// let schema = ParamSchema::new().param(ParamSpec::new("token", ParamType::String).required().secret());
+/// let mut ffi_schema = LibParamSchema::from(Some(&schema));
+/// assert_eq!(ParamSchema::from(&ffi_schema), schema);
+/// ffi_schema.free_contents();
+///
+/// let schema = ParamSchema::new().param(ParamSpec::new("db_password", ParamType::String).default_value("admin"));
+/// let mut ffi_schema = LibParamSchema::from(Some(&schema));
+/// let exported = &ParamSchema::from(&ffi_schema).params[0];
+/// assert!(exported.secret);
+/// assert_ne!(exported.default.as_deref(), Some("admin"));
+/// ffi_schema.free_contents();
+/// ```
+impl From<Option<&ParamSchema>> for LibParamSchema {
+    fn from(value: Option<&ParamSchema>) -> Self {
+        match value {
+            Some(schema) => LibParamSchema {
+                params: Array::from_vec_with_free_fn(
+                    schema.params.iter().map(ParamDescriptor::from).collect(),
+                    free_param_descriptor_array),
+                allow_unknown: schema.allow_unknown,
+            },
+            None => LibParamSchema {
+                params: Array::from_vec(Vec::new()),
+                allow_unknown: true,
+            },
+        }
+    }
+}
+
+impl From<&LibParamSchema> for ParamSchema {
+    fn from(value: &LibParamSchema) -> Self {
+        ParamSchema {
+            params: value.params.as_slice().iter().map(ParamSpec::from).collect(),
+            allow_unknown: value.allow_unknown,
+        }
+    }
+}
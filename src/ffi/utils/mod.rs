@@ -1 +1,2 @@
+pub mod panic;
 pub mod strings;
\ No newline at end of file
@@ -0,0 +1,53 @@
+use std::{any::Any, panic::AssertUnwindSafe, sync::{Mutex, MutexGuard, PoisonError}};
+
+use log::error;
+
+use crate::{
+    error::{Error, ERROR_CODE_LOCK_POISONED},
+    ffi::{shared::set_step_poisoned, types::module::ModuleHandle},
+};
+
+/// Runs a closure and catches a panic, so it doesn't unwind across the C ABI boundary.
+/// If the handle is provided, the step is marked as poisoned after panic.
+/// Returns an internal error describing the panic
+/// ```
+/// use torustiq_common::{error::ERROR_CODE_PANIC, ffi::{shared::is_step_poisoned, utils::panic::catch_panic}};
+/// assert_eq!(catch_panic("sum", Some(10), || 2 + 2), Ok(4));
+/// assert!(!is_step_poisoned(10));
+/// let e = catch_panic("div", Some(10), || panic!("boom")).unwrap_err();
+/// assert!(e.message.contains("boom"));
+/// assert_eq!(e.code, ERROR_CODE_PANIC);
+/// assert!(is_step_poisoned(10));
+/// ```
+pub fn catch_panic<R, F: FnOnce() -> R>(context: &str, h: Option<ModuleHandle>, f: F) -> Result<R, Error> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|e| {
+        let msg = format!("{}: panic occurred: {}", context, panic_message(e.as_ref()));
+        error!("{}", msg);
+        if let Some(h) = h {
+            set_step_poisoned(h, msg.clone());
+        }
+        Error::panic(msg)
+    })
+}
+
+fn panic_message(e: &(dyn Any + Send)) -> &str {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        return s;
+    }
+    match e.downcast_ref::<String>() {
+        Some(s) => s.as_str(),
+        None => "unknown error",
+    }
+}
+
+/// Acquires a lock. A poisoned mutex is reported as internal error
+pub fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> Result<MutexGuard<'a, T>, Error> {
+    m.lock().map_err(|_| Error::internal(format!("The lock of {} is poisoned by a panic in another thread", name))
+        .with_code(ERROR_CODE_LOCK_POISONED))
+}
+
+/// Acquires a lock even if the mutex is poisoned.
+/// Suitable for containers which stay consistent after panic, e.g. maps modified by a single insert
+pub fn lock_or_recover<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
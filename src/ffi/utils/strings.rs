@@ -8,6 +8,7 @@ use crate::ffi::types::std_types::{ConstBytePtr, ConstCharPtr};
 /// assert_eq!(strings::cchar_to_string(c"Hello, World!".as_ptr()),
 ///            String::from("Hello, World!"));
 /// ```
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub fn cchar_to_string(c: ConstCharPtr) -> String {
     unsafe { CStr::from_ptr(c).to_string_lossy().to_string() }
 }
@@ -19,6 +20,7 @@ pub fn cchar_to_string(c: ConstCharPtr) -> String {
 /// assert_eq!(strings::bytes_to_string_safe(c"Hello, World!".as_ptr() as *const u8, 13),
 ///            String::from("Hello, World!"));
 /// ```
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub fn bytes_to_string_safe(src: ConstBytePtr, len: usize) -> String {
     let mut dst: Vec<u8> = Vec::with_capacity(len);
     unsafe {
@@ -0,0 +1,392 @@
+//! Loads module libraries and resolves their exported functions
+
+use std::{ffi::OsStr, fmt};
+
+use libloading::Library;
+
+use crate::{
+    ffi::types::{
+        collections::Array,
+        functions as fn_defs,
+        module::{
+            LibInfo, ModuleHandle, ModuleKind, ModulePipelineProcessRecordFnResult,
+            ModulePipelineProcessRecordsFnResult, Record,
+        },
+        std_types::Uint,
+    },
+    host::symbols,
+    metrics::MetricSnapshot,
+    params::ParamSchema,
+    CURRENT_API_VERSION, MIN_SUPPORTED_API_VERSION,
+};
+
+/// An error occurred while loading a module library
+#[derive(Debug)]
+pub enum LoadError {
+    /// The shared library cannot be opened
+    Library(libloading::Error),
+    /// Some required symbols are not exported by library.
+    /// Missing optional symbols are listed too to give a complete picture
+    MissingSymbols {
+        required: Vec<&'static str>,
+        optional: Vec<&'static str>,
+    },
+    /// The API version of library is outside of the range supported by host
+    IncompatibleApiVersion {
+        found: u32,
+        supported_min: u32,
+        supported_max: u32,
+    },
+    /// Library implements a different kind of module (pipeline / listener)
+    WrongModuleKind {
+        expected: ModuleKind,
+        found: ModuleKind,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Library(e) => write!(f, "failed to open the module library: {}", e),
+            LoadError::MissingSymbols { required, .. } =>
+                write!(f, "required symbols are not exported by module library: {}", required.join(", ")),
+            LoadError::IncompatibleApiVersion { found, supported_min, supported_max } =>
+                write!(f, "module API version {} is not supported; expected {}..={}", found, supported_min, supported_max),
+            LoadError::WrongModuleKind { expected, found } =>
+                write!(f, "expected a {:?} module, but library contains a {:?} module", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Library(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<libloading::Error> for LoadError {
+    fn from(value: libloading::Error) -> Self {
+        LoadError::Library(value)
+    }
+}
+
+/// Collects symbols from a library and keeps track of missing ones
+struct SymbolResolver<'a> {
+    library: &'a Library,
+    missing_required: Vec<&'static str>,
+    missing_optional: Vec<&'static str>,
+}
+
+impl<'a> SymbolResolver<'a> {
+    fn new(library: &'a Library) -> Self {
+        SymbolResolver {
+            library,
+            missing_required: Vec::new(),
+            missing_optional: Vec::new(),
+        }
+    }
+
+    fn lookup<T: Copy>(&self, name: &'static str) -> Option<T> {
+        unsafe { self.library.get::<T>(name.as_bytes()) }
+            .ok()
+            .map(|s| *s)
+    }
+
+    /// Returns a symbol which must be present. A missing symbol is recorded and reported by `finish`
+    fn required<T: Copy>(&mut self, name: &'static str) -> Option<T> {
+        let s = self.lookup(name);
+        if s.is_none() {
+            self.missing_required.push(name);
+        }
+        s
+    }
+
+    fn optional<T: Copy>(&mut self, name: &'static str) -> Option<T> {
+        let s = self.lookup(name);
+        if s.is_none() {
+            self.missing_optional.push(name);
+        }
+        s
+    }
+
+    /// Returns missing optional symbols or an error if any required symbol is missing
+    fn finish(self) -> Result<Vec<&'static str>, LoadError> {
+        if self.missing_required.is_empty() {
+            return Ok(self.missing_optional);
+        }
+        Err(LoadError::MissingSymbols {
+            required: self.missing_required,
+            optional: self.missing_optional,
+        })
+    }
+}
+
+/// Checks the module information returned by library
+fn validate_lib_info(info: &LibInfo, expected_kind: ModuleKind) -> Result<(), LoadError> {
+    if info.api_version < MIN_SUPPORTED_API_VERSION || info.api_version > CURRENT_API_VERSION {
+        return Err(LoadError::IncompatibleApiVersion {
+            found: info.api_version,
+            supported_min: MIN_SUPPORTED_API_VERSION,
+            supported_max: CURRENT_API_VERSION,
+        });
+    }
+    if info.kind != expected_kind {
+        return Err(LoadError::WrongModuleKind {
+            expected: expected_kind,
+            found: info.kind.clone(),
+        });
+    }
+    Ok(())
+}
+
+fn get_param_schema(f: fn_defs::LibGetParamSchemaFn) -> ParamSchema {
+    let mut schema = f();
+    let result = ParamSchema::from(&schema);
+    schema.free_contents();
+    result
+}
+
+fn get_metrics(f: fn_defs::LibGetMetricsFn) -> Vec<MetricSnapshot> {
+    let mut arr = f();
+    let result = arr.as_slice().iter().map(MetricSnapshot::from).collect();
+    arr.free_contents();
+    result
+}
+
+unsafe fn open_library<P: AsRef<OsStr>>(path: P) -> Result<Library, LoadError> {
+    Ok(Library::new(path)?)
+}
+
+/// A pipeline module library with all its functions resolved.
+/// Function pointers are valid as long as this object is alive
+pub struct LoadedPipelineLibrary {
+    pub info: LibInfo,
+    pub get_info: fn_defs::LibGetInfoFn,
+    pub get_param_schema: Option<fn_defs::LibGetParamSchemaFn>,
+    pub get_metrics: Option<fn_defs::LibGetMetricsFn>,
+    pub init: fn_defs::LibPipelineInitFn,
+    pub configure: fn_defs::ModulePipelineConfigureFn,
+    pub start: fn_defs::StepStartFn,
+    pub process_record: fn_defs::ModulePipelineProcessRecordFn,
+    /// Use `process_records` method to fall back to single records if library doesn't export this function
+    pub process_records: Option<fn_defs::ModulePipelineProcessRecordsFn>,
+    pub set_param: fn_defs::StepSetParamFn,
+    pub record_ack: Option<fn_defs::ModulePipelineRecordAckFn>,
+    pub free_record: fn_defs::ModuleFreeRecordFn,
+    pub free_char: fn_defs::ModuleFreeCharPtrFn,
+    pub shutdown: Option<fn_defs::ModuleStepShutdownFn>,
+    pub pause: Option<fn_defs::StepPauseFn>,
+    pub resume: Option<fn_defs::StepResumeFn>,
+    pub drain: Option<fn_defs::StepDrainFn>,
+    pub get_poison_status: Option<fn_defs::StepGetPoisonStatusFn>,
+    pub set_log_level: Option<fn_defs::StepSetLogLevelFn>,
+    missing_optional_symbols: Vec<&'static str>,
+    _library: Library,
+}
+
+impl LoadedPipelineLibrary {
+    /// Loads a pipeline module library and validates its API version and module kind
+    /// ```no_run
+    /// use torustiq_common::host::loader::LoadedPipelineLibrary;
+    /// let lib = unsafe { LoadedPipelineLibrary::load("./libtorustiq_module_stdio.so") }.unwrap();
+    /// println!("Optional symbols not exported: {:?}", lib.missing_optional_symbols());
+    /// ```
+    ///
+    /// # Safety
+    /// Loading a library runs its initialization routines; the library must be a Torustiq module
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, LoadError> {
+        let library = open_library(path)?;
+        let mut r = SymbolResolver::new(&library);
+        let get_info = r.required::<fn_defs::LibGetInfoFn>(symbols::LIB_GET_INFO);
+        let get_param_schema = r.optional::<fn_defs::LibGetParamSchemaFn>(symbols::LIB_GET_PARAM_SCHEMA);
+        let get_metrics = r.optional::<fn_defs::LibGetMetricsFn>(symbols::LIB_GET_METRICS);
+        let init = r.required::<fn_defs::LibPipelineInitFn>(symbols::LIB_PIPELINE_INIT);
+        let configure = r.required::<fn_defs::ModulePipelineConfigureFn>(symbols::MODULE_PIPELINE_CONFIGURE);
+        let start = r.required::<fn_defs::StepStartFn>(symbols::MODULE_COMMON_START);
+        let process_record = r.required::<fn_defs::ModulePipelineProcessRecordFn>(symbols::MODULE_PIPELINE_PROCESS_RECORD);
+        let process_records = r.optional::<fn_defs::ModulePipelineProcessRecordsFn>(symbols::MODULE_PIPELINE_PROCESS_RECORDS);
+        let set_param = r.required::<fn_defs::StepSetParamFn>(symbols::MODULE_COMMON_SET_PARAM);
+        let record_ack = r.optional::<fn_defs::ModulePipelineRecordAckFn>(symbols::MODULE_PIPELINE_RECORD_ACK);
+        let free_record = r.required::<fn_defs::ModuleFreeRecordFn>(symbols::MODULE_PIPELINE_FREE_RECORD);
+        let free_char = r.required::<fn_defs::ModuleFreeCharPtrFn>(symbols::MODULE_COMMON_FREE_CHAR);
+        let shutdown = r.optional::<fn_defs::ModuleStepShutdownFn>(symbols::MODULE_COMMON_SHUTDOWN);
+        let pause = r.optional::<fn_defs::StepPauseFn>(symbols::MODULE_COMMON_PAUSE);
+        let resume = r.optional::<fn_defs::StepResumeFn>(symbols::MODULE_COMMON_RESUME);
+        let drain = r.optional::<fn_defs::StepDrainFn>(symbols::MODULE_COMMON_DRAIN);
+        let get_poison_status = r.optional::<fn_defs::StepGetPoisonStatusFn>(symbols::MODULE_COMMON_GET_POISON_STATUS);
+        let set_log_level = r.optional::<fn_defs::StepSetLogLevelFn>(symbols::MODULE_COMMON_SET_LOG_LEVEL);
+        let missing_optional_symbols = r.finish()?;
+
+        // All required symbols are resolved at this point
+        let get_info = get_info.unwrap();
+        let info = get_info();
+        validate_lib_info(&info, ModuleKind::Pipeline)?;
+
+        Ok(LoadedPipelineLibrary {
+            info,
+            get_info,
+            get_param_schema,
+            get_metrics,
+            init: init.unwrap(),
+            configure: configure.unwrap(),
+            start: start.unwrap(),
+            process_record: process_record.unwrap(),
+            process_records,
+            set_param: set_param.unwrap(),
+            record_ack,
+            free_record: free_record.unwrap(),
+            free_char: free_char.unwrap(),
+            shutdown,
+            pause,
+            resume,
+            drain,
+            get_poison_status,
+            set_log_level,
+            missing_optional_symbols,
+            _library: library,
+        })
+    }
+
+    /// Returns names of optional symbols which are not exported by library
+    pub fn missing_optional_symbols(&self) -> &[&'static str] {
+        &self.missing_optional_symbols
+    }
+
+    /// Returns params accepted by module. None if library doesn't export the schema
+    pub fn param_schema(&self) -> Option<ParamSchema> {
+        self.get_param_schema.map(get_param_schema)
+    }
+
+    /// Returns current metrics of all steps of library. Empty if library doesn't export metrics
+    pub fn metrics(&self) -> Vec<MetricSnapshot> {
+        self.get_metrics.map(get_metrics).unwrap_or_default()
+    }
+
+    /// Passes a batch of records to step. If library doesn't export the batch function,
+    /// records are passed one by one until a record is rejected. Records which are processed,
+    /// but not consumed by step are freed here, so they are reported as consumed
+    pub fn process_records(&self, h: ModuleHandle, records: Array<Record>) -> ModulePipelineProcessRecordsFnResult {
+        if let Some(process_records) = self.process_records {
+            return process_records(h, records);
+        }
+        let mut consumed: Uint = 0;
+        for r in records.as_slice() {
+            let result = (self.process_record)(h, *r);
+            match result {
+                ModulePipelineProcessRecordFnResult::Ok(true) => {},
+                ModulePipelineProcessRecordFnResult::Ok(false) => {
+                    let mut r = *r;
+                    r.free_contents();
+                },
+                ModulePipelineProcessRecordFnResult::ErrWrongModuleHandle(h, c) =>
+                    return ModulePipelineProcessRecordsFnResult::ErrWrongModuleHandle(h, consumed + c as Uint),
+                ModulePipelineProcessRecordFnResult::ErrBusy(h, c) =>
+                    return ModulePipelineProcessRecordsFnResult::ErrBusy(h, consumed + c as Uint),
+                ModulePipelineProcessRecordFnResult::ErrMisc(msg, c) =>
+                    return ModulePipelineProcessRecordsFnResult::ErrMisc(msg, consumed + c as Uint),
+            }
+            consumed += 1;
+        }
+        ModulePipelineProcessRecordsFnResult::Ok(consumed)
+    }
+}
+
+/// A listener module library with all its functions resolved.
+/// Function pointers are valid as long as this object is alive
+pub struct LoadedListenerLibrary {
+    pub info: LibInfo,
+    pub get_info: fn_defs::LibGetInfoFn,
+    pub get_param_schema: Option<fn_defs::LibGetParamSchemaFn>,
+    pub get_metrics: Option<fn_defs::LibGetMetricsFn>,
+    pub init: fn_defs::LibListenerInitFn,
+    pub configure: fn_defs::ModuleListenerConfigureFn,
+    pub set_param: fn_defs::StepSetParamFn,
+    pub free_char: fn_defs::ModuleFreeCharPtrFn,
+    pub start: Option<fn_defs::StepStartFn>,
+    pub shutdown: Option<fn_defs::ModuleStepShutdownFn>,
+    pub pause: Option<fn_defs::StepPauseFn>,
+    pub resume: Option<fn_defs::StepResumeFn>,
+    pub drain: Option<fn_defs::StepDrainFn>,
+    pub get_poison_status: Option<fn_defs::StepGetPoisonStatusFn>,
+    pub set_log_level: Option<fn_defs::StepSetLogLevelFn>,
+    pub record_received: Option<fn_defs::ModuleListenerRecordRcvFn>,
+    pub record_send_success: Option<fn_defs::ModuleListenerRecordSendSuccessFn>,
+    pub record_send_failure: Option<fn_defs::ModuleListenerRecordSendFailureFn>,
+    missing_optional_symbols: Vec<&'static str>,
+    _library: Library,
+}
+
+impl LoadedListenerLibrary {
+    /// Loads a listener module library and validates its API version and module kind
+    ///
+    /// # Safety
+    /// Loading a library runs its initialization routines; the library must be a Torustiq module
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, LoadError> {
+        let library = open_library(path)?;
+        let mut r = SymbolResolver::new(&library);
+        let get_info = r.required::<fn_defs::LibGetInfoFn>(symbols::LIB_GET_INFO);
+        let get_param_schema = r.optional::<fn_defs::LibGetParamSchemaFn>(symbols::LIB_GET_PARAM_SCHEMA);
+        let get_metrics = r.optional::<fn_defs::LibGetMetricsFn>(symbols::LIB_GET_METRICS);
+        let init = r.required::<fn_defs::LibListenerInitFn>(symbols::LIB_LISTENER_INIT);
+        let configure = r.required::<fn_defs::ModuleListenerConfigureFn>(symbols::MODULE_LISTENER_CONFIGURE);
+        let set_param = r.required::<fn_defs::StepSetParamFn>(symbols::MODULE_COMMON_SET_PARAM);
+        let free_char = r.required::<fn_defs::ModuleFreeCharPtrFn>(symbols::MODULE_COMMON_FREE_CHAR);
+        let start = r.optional::<fn_defs::StepStartFn>(symbols::MODULE_COMMON_START);
+        let shutdown = r.optional::<fn_defs::ModuleStepShutdownFn>(symbols::MODULE_COMMON_SHUTDOWN);
+        let pause = r.optional::<fn_defs::StepPauseFn>(symbols::MODULE_COMMON_PAUSE);
+        let resume = r.optional::<fn_defs::StepResumeFn>(symbols::MODULE_COMMON_RESUME);
+        let drain = r.optional::<fn_defs::StepDrainFn>(symbols::MODULE_COMMON_DRAIN);
+        let get_poison_status = r.optional::<fn_defs::StepGetPoisonStatusFn>(symbols::MODULE_COMMON_GET_POISON_STATUS);
+        let set_log_level = r.optional::<fn_defs::StepSetLogLevelFn>(symbols::MODULE_COMMON_SET_LOG_LEVEL);
+        let record_received = r.optional::<fn_defs::ModuleListenerRecordRcvFn>(symbols::MODULE_LISTENER_RECORD_RECEIVED);
+        let record_send_success = r.optional::<fn_defs::ModuleListenerRecordSendSuccessFn>(symbols::MODULE_LISTENER_RECORD_SEND_SUCCESS);
+        let record_send_failure = r.optional::<fn_defs::ModuleListenerRecordSendFailureFn>(symbols::MODULE_LISTENER_RECORD_SEND_FAILURE);
+        let missing_optional_symbols = r.finish()?;
+
+        // All required symbols are resolved at this point
+        let get_info = get_info.unwrap();
+        let info = get_info();
+        validate_lib_info(&info, ModuleKind::Listener)?;
+
+        Ok(LoadedListenerLibrary {
+            info,
+            get_info,
+            get_param_schema,
+            get_metrics,
+            init: init.unwrap(),
+            configure: configure.unwrap(),
+            set_param: set_param.unwrap(),
+            free_char: free_char.unwrap(),
+            start,
+            shutdown,
+            pause,
+            resume,
+            drain,
+            get_poison_status,
+            set_log_level,
+            record_received,
+            record_send_success,
+            record_send_failure,
+            missing_optional_symbols,
+            _library: library,
+        })
+    }
+
+    /// Returns names of optional symbols which are not exported by library
+    pub fn missing_optional_symbols(&self) -> &[&'static str] {
+        &self.missing_optional_symbols
+    }
+
+    /// Returns params accepted by module. None if library doesn't export the schema
+    pub fn param_schema(&self) -> Option<ParamSchema> {
+        self.get_param_schema.map(get_param_schema)
+    }
+
+    /// Returns current metrics of all steps of library. Empty if library doesn't export metrics
+    pub fn metrics(&self) -> Vec<MetricSnapshot> {
+        self.get_metrics.map(get_metrics).unwrap_or_default()
+    }
+}
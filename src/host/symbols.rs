@@ -0,0 +1,28 @@
+//! Names of symbols exported by module libraries
+
+pub const LIB_GET_INFO: &str = "torustiq_lib_get_info";
+pub const LIB_GET_METRICS: &str = "torustiq_lib_get_metrics";
+pub const LIB_GET_PARAM_SCHEMA: &str = "torustiq_lib_get_param_schema";
+pub const LIB_PIPELINE_INIT: &str = "torustiq_lib_pipeline_init";
+pub const LIB_LISTENER_INIT: &str = "torustiq_lib_listener_init";
+
+pub const MODULE_COMMON_SET_PARAM: &str = "torustiq_module_common_set_param";
+pub const MODULE_COMMON_START: &str = "torustiq_module_common_start";
+pub const MODULE_COMMON_SHUTDOWN: &str = "torustiq_module_common_shutdown";
+pub const MODULE_COMMON_PAUSE: &str = "torustiq_module_common_pause";
+pub const MODULE_COMMON_RESUME: &str = "torustiq_module_common_resume";
+pub const MODULE_COMMON_DRAIN: &str = "torustiq_module_common_drain";
+pub const MODULE_COMMON_SET_LOG_LEVEL: &str = "torustiq_module_common_set_log_level";
+pub const MODULE_COMMON_GET_POISON_STATUS: &str = "torustiq_module_common_get_poison_status";
+pub const MODULE_COMMON_FREE_CHAR: &str = "torustiq_module_common_free_char";
+
+pub const MODULE_PIPELINE_CONFIGURE: &str = "torustiq_module_pipeline_configure";
+pub const MODULE_PIPELINE_PROCESS_RECORD: &str = "torustiq_module_pipeline_process_record";
+pub const MODULE_PIPELINE_PROCESS_RECORDS: &str = "torustiq_module_pipeline_process_records";
+pub const MODULE_PIPELINE_RECORD_ACK: &str = "torustiq_module_pipeline_record_ack";
+pub const MODULE_PIPELINE_FREE_RECORD: &str = "torustiq_module_pipeline_free_record";
+
+pub const MODULE_LISTENER_CONFIGURE: &str = "torustiq_module_listener_configure";
+pub const MODULE_LISTENER_RECORD_RECEIVED: &str = "torustiq_module_listener_record_received";
+pub const MODULE_LISTENER_RECORD_SEND_SUCCESS: &str = "torustiq_module_listener_record_send_success";
+pub const MODULE_LISTENER_RECORD_SEND_FAILURE: &str = "torustiq_module_listener_record_send_failure";
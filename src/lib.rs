@@ -1,5 +1,21 @@
+pub mod dead_letter;
+pub mod error;
 pub mod ffi;
+#[cfg(feature="host")]
+pub mod host;
+pub mod lifecycle;
 pub mod logging;
+pub mod metrics;
+pub mod params;
 pub mod pipeline;
+pub mod record;
+pub mod secret;
+pub mod trace;
 
-pub const CURRENT_API_VERSION: u32 = 1;
\ No newline at end of file
+/// Version of the C ABI. Incremented whenever the layout of exported types changes
+pub const CURRENT_API_VERSION: u32 = 3;
+/// The oldest API version this library can still communicate with.
+/// Version 3 changed the layout of `Record` and other shared types, so hosts of version 2 cannot pass
+/// records to this library at all. Such hosts get an 'incompatible API version' error on initialization
+/// instead of falling back to an older version
+pub const MIN_SUPPORTED_API_VERSION: u32 = 3;
\ No newline at end of file
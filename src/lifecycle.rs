@@ -0,0 +1,173 @@
+//! Lifecycle states of steps: running, paused, draining and terminated.
+//! Paused steps reject records with the 'busy' status, so host retries them later.
+//! Draining steps reject new records and terminate once the buffered records are processed
+
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    error::{Error, ERROR_CODE_STEP_NOT_ACCEPTING},
+    ffi::{
+        shared::{is_step_poisoned, notify_step_terminated},
+        types::module::{ModuleHandle, StepTerminationStatus},
+        utils::panic::lock_or_recover,
+    },
+};
+
+/// States of steps which are not running. Waiters are notified on every change
+static STEP_STATES: Lazy<(Mutex<HashMap<ModuleHandle, StepState>>, Condvar)> = Lazy::new(|| {
+    (Mutex::new(HashMap::new()), Condvar::new())
+});
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepState {
+    Running,
+    /// The step doesn't accept records until resumed. Sources should stop producing records
+    Paused,
+    /// The step doesn't accept new records and processes the buffered ones
+    Draining,
+    /// Host is notified about termination. The step doesn't accept records anymore
+    Terminated(StepTerminationStatus),
+}
+
+pub fn get_step_state(h: ModuleHandle) -> StepState {
+    lock_or_recover(&STEP_STATES.0).get(&h).cloned().unwrap_or(StepState::Running)
+}
+
+fn set_step_state(h: ModuleHandle, state: StepState) {
+    let mut states = lock_or_recover(&STEP_STATES.0);
+    match state {
+        StepState::Running => states.remove(&h),
+        _ => states.insert(h, state),
+    };
+    STEP_STATES.1.notify_all();
+}
+
+/// Makes a step running again, e.g. when it's configured with a handle used before
+pub fn reset_step_state(h: ModuleHandle) {
+    set_step_state(h, StepState::Running);
+}
+
+/// Pauses a running step. Pausing a paused step has no effect
+/// ```
+/// use torustiq_common::lifecycle::{get_step_state, pause_step, resume_step, StepState};
+/// pause_step(7).unwrap();
+/// assert_eq!(get_step_state(7), StepState::Paused);
+/// resume_step(7).unwrap();
+/// assert_eq!(get_step_state(7), StepState::Running);
+/// ```
+pub fn pause_step(h: ModuleHandle) -> Result<(), Error> {
+    transition(h, "pause", |s| matches!(s, StepState::Running | StepState::Paused), StepState::Paused)
+}
+
+/// Resumes a paused step. Resuming a running step has no effect
+pub fn resume_step(h: ModuleHandle) -> Result<(), Error> {
+    transition(h, "resume", |s| matches!(s, StepState::Running | StepState::Paused), StepState::Running)
+}
+
+/// Switches a running or paused step to draining
+pub fn begin_drain(h: ModuleHandle) -> Result<(), Error> {
+    transition(h, "drain", |s| matches!(s, StepState::Running | StepState::Paused), StepState::Draining)
+}
+
+fn transition<F: Fn(StepState) -> bool>(h: ModuleHandle, op: &str, allowed: F, to: StepState) -> Result<(), Error> {
+    let mut states = lock_or_recover(&STEP_STATES.0);
+    let current = states.get(&h).cloned().unwrap_or(StepState::Running);
+    if !allowed(current) {
+        return Err(Error::internal(format!("Cannot {} step {} in state {:?}", op, h, current))
+            .with_code(ERROR_CODE_STEP_NOT_ACCEPTING));
+    }
+    match to {
+        StepState::Running => states.remove(&h),
+        _ => states.insert(h, to),
+    };
+    STEP_STATES.1.notify_all();
+    Ok(())
+}
+
+/// Checks if step accepts records. Returns an error for draining and terminated steps;
+/// Ok(false) means the step is paused and the record should be retried later
+pub fn check_accepts_records(h: ModuleHandle) -> Result<bool, Error> {
+    match get_step_state(h) {
+        StepState::Running => Ok(true),
+        StepState::Paused => Ok(false),
+        state => Err(Error::internal(format!("Step {} doesn't accept records in state {:?}", h, state))
+            .with_code(ERROR_CODE_STEP_NOT_ACCEPTING)),
+    }
+}
+
+/// Blocks while step is paused. Returns false if the timeout expired before the step was resumed.
+/// Sources call this function before producing records
+pub fn wait_while_paused(h: ModuleHandle, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut states = lock_or_recover(&STEP_STATES.0);
+    while states.get(&h) == Some(&StepState::Paused) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return false;
+        }
+        states = match STEP_STATES.1.wait_timeout(states, left) {
+            Ok((s, _)) => s,
+            Err(e) => e.into_inner().0,
+        };
+    }
+    true
+}
+
+/// Marks the step as terminated and notifies host. Host is notified only once per step,
+/// so concurrent drain completion and timeout don't produce two notifications.
+/// Poisoned steps are always reported as failed. Returns false if step is already terminated
+pub fn terminate_step(h: ModuleHandle, status: StepTerminationStatus) -> bool {
+    terminate_if(h, status, |s| !matches!(s, StepState::Terminated(_)))
+}
+
+/// Finishes draining of step with the 'drained' status. Has no effect if the step is not draining
+pub fn complete_drain(h: ModuleHandle) -> bool {
+    terminate_if(h, StepTerminationStatus::Drained, |s| s == StepState::Draining)
+}
+
+fn terminate_if<F: Fn(StepState) -> bool>(h: ModuleHandle, status: StepTerminationStatus, allowed: F) -> bool {
+    let status = match is_step_poisoned(h) {
+        true => StepTerminationStatus::Failed,
+        false => status,
+    };
+    {
+        let mut states = lock_or_recover(&STEP_STATES.0);
+        if !allowed(states.get(&h).cloned().unwrap_or(StepState::Running)) {
+            return false;
+        }
+        states.insert(h, StepState::Terminated(status));
+        STEP_STATES.1.notify_all();
+    }
+    notify_step_terminated(h, status);
+    true
+}
+
+/// Terminates the step with the 'drain timeout' status if it's still draining after timeout.
+/// Zero timeout means no timeout, so no watchdog is started
+pub fn spawn_drain_watchdog(h: ModuleHandle, timeout: Duration) {
+    if timeout.is_zero() {
+        return;
+    }
+    std::thread::spawn(move || {
+        let deadline = Instant::now() + timeout;
+        let mut states = lock_or_recover(&STEP_STATES.0);
+        while states.get(&h) == Some(&StepState::Draining) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                drop(states);
+                terminate_if(h, StepTerminationStatus::DrainTimeout, |s| s == StepState::Draining);
+                return;
+            }
+            states = match STEP_STATES.1.wait_timeout(states, left) {
+                Ok((s, _)) => s,
+                Err(e) => e.into_inner().0,
+            };
+        }
+    });
+}
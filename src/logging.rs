@@ -1,7 +1,150 @@
+//! Logging for module libraries.
+//! If host provides a log callback, log records are forwarded to host with a handle of step which produced them.
+//! Otherwise, a logger configured by 'LOG_LEVEL' environment variable is used
+
+use std::{cell::Cell, collections::HashMap, ffi::CString, sync::{atomic::{AtomicBool, Ordering}, Mutex}};
+
 use env_logger::{Builder, Env};
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+use crate::ffi::{
+    shared::get_common_lib_configuration,
+    types::{
+        functions::ModuleLogCb,
+        logging::LogRecord,
+        module::ModuleHandle,
+    },
+    utils::panic::lock_or_recover,
+};
+
+/// Log levels of steps which differ from the default one
+static STEP_LOG_LEVELS: Lazy<Mutex<HashMap<ModuleHandle, LevelFilter>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+static DEFAULT_LOG_LEVEL: Mutex<LevelFilter> = Mutex::new(LevelFilter::Info);
 
+/// Log levels are managed by this module only if records are passed to host
+static HOST_LOGGER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// A step which is served by the current thread
+    static CURRENT_STEP: Cell<Option<ModuleHandle>> = const { Cell::new(None) };
+}
+
+/// Installs a logger. Log records are passed to host if it provides a log callback
 pub fn init_logger() {
-    let env = Env::default()
-        .filter_or("LOG_LEVEL", "info");
-    Builder::from_env(env).init();
-}
\ No newline at end of file
+    let cfg = get_common_lib_configuration();
+    match cfg.as_ref().and_then(|c| c.log_cb) {
+        Some(cb) => {
+            *lock_or_recover(&DEFAULT_LOG_LEVEL) = cfg.map(|c| c.log_level.into()).unwrap_or(LevelFilter::Info);
+            // A logger might be already installed, e.g. if library is initialized twice
+            if log::set_boxed_logger(Box::new(HostLogger { cb })).is_ok() {
+                HOST_LOGGER_INSTALLED.store(true, Ordering::SeqCst);
+            }
+            update_max_level();
+        },
+        None => {
+            let env = Env::default()
+                .filter_or("LOG_LEVEL", "info");
+            let _ = Builder::from_env(env).try_init();
+        },
+    }
+}
+
+/// Forwards log records to host
+pub struct HostLogger {
+    cb: ModuleLogCb,
+}
+
+impl HostLogger {
+    pub fn new(cb: ModuleLogCb) -> Self {
+        HostLogger { cb }
+    }
+}
+
+impl Log for HostLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= get_log_level(current_step())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let step = current_step();
+        // NUL characters would make the conversion fail
+        let target = CString::new(record.target().replace('\0', " ")).unwrap_or_default();
+        let message = CString::new(record.args().to_string().replace('\0', " ")).unwrap_or_default();
+        (self.cb)(LogRecord {
+            level: record.level().into(),
+            step: step.unwrap_or_default(),
+            has_step: step.is_some(),
+            target: target.as_ptr(),
+            message: message.as_ptr(),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Restores the previous step of thread when dropped
+pub struct StepScope {
+    previous: Option<ModuleHandle>,
+}
+
+impl Drop for StepScope {
+    fn drop(&mut self) {
+        CURRENT_STEP.with(|s| s.set(self.previous));
+    }
+}
+
+/// Attributes log records of the current thread to step until the returned scope is dropped.
+/// Worker threads of step should call this function once on start
+/// ```
+/// use torustiq_common::logging::{current_step, enter_step};
+/// {
+///     let _scope = enter_step(4);
+///     assert_eq!(current_step(), Some(4));
+/// }
+/// assert_eq!(current_step(), None);
+/// ```
+pub fn enter_step(h: ModuleHandle) -> StepScope {
+    StepScope {
+        previous: CURRENT_STEP.with(|s| s.replace(Some(h))),
+    }
+}
+
+pub fn current_step() -> Option<ModuleHandle> {
+    CURRENT_STEP.with(|s| s.get())
+}
+
+/// Sets the log level of step. Applies to messages logged while the step is current for thread
+pub fn set_step_log_level(h: ModuleHandle, level: LevelFilter) {
+    lock_or_recover(&STEP_LOG_LEVELS).insert(h, level);
+    update_max_level();
+}
+
+/// Sets the log level for messages which are not attributed to any step, and for steps without own level
+pub fn set_default_log_level(level: LevelFilter) {
+    *lock_or_recover(&DEFAULT_LOG_LEVEL) = level;
+    update_max_level();
+}
+
+/// Returns the effective log level of step, or the default level if no step is provided
+pub fn get_log_level(h: Option<ModuleHandle>) -> LevelFilter {
+    let step_level = h.and_then(|h| lock_or_recover(&STEP_LOG_LEVELS).get(&h).cloned());
+    step_level.unwrap_or_else(|| *lock_or_recover(&DEFAULT_LOG_LEVEL))
+}
+
+/// Lets the log facade skip messages which are not enabled for any step
+fn update_max_level() {
+    if !HOST_LOGGER_INSTALLED.load(Ordering::SeqCst) {
+        return;
+    }
+    let default_level = *lock_or_recover(&DEFAULT_LOG_LEVEL);
+    let max_level = lock_or_recover(&STEP_LOG_LEVELS).values()
+        .fold(default_level, |acc, l| acc.max(*l));
+    log::set_max_level(max_level);
+}
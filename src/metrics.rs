@@ -0,0 +1,378 @@
+//! Metrics of module steps: counters, gauges and histograms scoped to a step handle.
+//! Host pulls a snapshot of all metrics via `torustiq_lib_get_metrics`
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::ffi::{types::module::ModuleHandle, utils::panic::lock_or_recover};
+
+type MetricRegistry = HashMap<ModuleHandle, BTreeMap<String, RegisteredMetric>>;
+
+/// Metrics per step. Metrics of a step are sorted by name
+static METRICS: Lazy<Mutex<MetricRegistry>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+/// Default histogram buckets for durations in seconds
+pub const DEFAULT_DURATION_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    /// A value which only grows, e.g. number of processed records
+    Counter,
+    /// A value which goes up and down, e.g. queue depth
+    Gauge,
+    /// A distribution of observed values, e.g. processing latency
+    Histogram,
+}
+
+struct RegisteredMetric {
+    help: String,
+    metric: Metric,
+}
+
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    GaugeFn(Arc<dyn Fn() -> f64 + Send + Sync>),
+    Histogram(Histogram),
+}
+
+/// A monotonically increasing counter. Cloned handles refer to the same value
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, v: u64) {
+        self.0.fetch_add(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value which can go up and down. Cloned handles refer to the same value
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn add(&self, v: f64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+            |bits| Some((f64::from_bits(bits) + v).to_bits()));
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Counts observed values in buckets. Cloned handles refer to the same data
+#[derive(Clone)]
+pub struct Histogram(Arc<Mutex<HistogramData>>);
+
+struct HistogramData {
+    /// Upper bounds of buckets, sorted
+    bounds: Vec<f64>,
+    /// Non-cumulative counts per bucket. The last one is for values above all bounds
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        Histogram(Arc::new(Mutex::new(HistogramData {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum: 0.0,
+            count: 0,
+        })))
+    }
+
+    pub fn observe(&self, v: f64) {
+        let mut h = lock_or_recover(&self.0);
+        let i = h.bounds.iter().position(|b| v <= *b).unwrap_or(h.bounds.len());
+        h.counts[i] += 1;
+        h.sum += v;
+        h.count += 1;
+    }
+
+    pub fn observe_duration(&self, d: Duration) {
+        self.observe(d.as_secs_f64());
+    }
+
+    fn value(&self) -> MetricValue {
+        let h = lock_or_recover(&self.0);
+        let mut cumulative = 0;
+        let buckets = h.bounds.iter().zip(h.counts.iter())
+            .map(|(b, c)| {
+                cumulative += c;
+                (*b, cumulative)
+            })
+            .collect();
+        MetricValue::Histogram { buckets, sum: h.sum, count: h.count }
+    }
+}
+
+/// A value of metric at the moment of snapshot
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(f64),
+    /// Buckets are pairs of upper bound and cumulative count. The implicit '+Inf' bucket equals 'count'
+    Histogram { buckets: Vec<(f64, u64)>, sum: f64, count: u64 },
+}
+
+impl MetricValue {
+    pub fn kind(&self) -> MetricKind {
+        match self {
+            MetricValue::Counter(_) => MetricKind::Counter,
+            MetricValue::Gauge(_) => MetricKind::Gauge,
+            MetricValue::Histogram { .. } => MetricKind::Histogram,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricSnapshot {
+    pub step: ModuleHandle,
+    pub name: String,
+    pub help: String,
+    pub value: MetricValue,
+}
+
+fn register<F: FnOnce() -> Metric>(h: ModuleHandle, name: &str, help: &str, create: F) -> MutexGuard<'static, MetricRegistry> {
+    let mut metrics = lock_or_recover(&METRICS);
+    metrics.entry(h).or_default()
+        .entry(name.to_string())
+        .or_insert_with(|| RegisteredMetric { help: help.to_string(), metric: create() });
+    metrics
+}
+
+/// Returns a counter of step. The counter is registered on first call; later calls return the same counter.
+/// Handles are cheap to clone, so keep them instead of calling this function per record.
+/// If the name is taken by a metric of another kind, a warning is logged and the returned counter is not reported
+/// ```
+/// use torustiq_common::metrics::{counter, snapshot, MetricValue};
+/// let records = counter(11, "records_total", "Records processed");
+/// records.inc();
+/// counter(11, "records_total", "Records processed").add(2);
+/// assert_eq!(snapshot(Some(11))[0].value, MetricValue::Counter(3));
+/// ```
+pub fn counter(h: ModuleHandle, name: &str, help: &str) -> Counter {
+    let registered = match &register(h, name, help, || Metric::Counter(Counter::default()))[&h][name].metric {
+        Metric::Counter(c) => Some(c.clone()),
+        _ => None,
+    };
+    // The name is taken by metric of another kind. The returned counter is not reported
+    registered.unwrap_or_else(|| {
+        warn_kind_mismatch(h, name, "counter");
+        Counter::default()
+    })
+}
+
+/// Returns a gauge of step. The gauge is registered on first call; later calls return the same gauge.
+/// The warning about a name taken by another kind is logged without holding the metrics lock
+/// ```
+/// use torustiq_common::metrics::{counter, gauge, snapshot};
+///
+/// // A logger which reads metrics, e.g. to attach them to log records
+/// struct MetricsLogger;
+///
+/// impl log::Log for MetricsLogger {
+///     fn enabled(&self, _: &log::Metadata) -> bool { true }
+///     fn log(&self, _: &log::Record) { let _ = snapshot(None); }
+///     fn flush(&self) {}
+/// }
+///
+/// log::set_logger(&MetricsLogger).unwrap();
+/// log::set_max_level(log::LevelFilter::Warn);
+/// counter(13, "queue_depth", "Queue depth");
+/// gauge(13, "queue_depth", "Queue depth").set(1.0);
+/// ```
+pub fn gauge(h: ModuleHandle, name: &str, help: &str) -> Gauge {
+    let registered = match &register(h, name, help, || Metric::Gauge(Gauge::default()))[&h][name].metric {
+        Metric::Gauge(g) => Some(g.clone()),
+        _ => None,
+    };
+    registered.unwrap_or_else(|| {
+        warn_kind_mismatch(h, name, "gauge");
+        Gauge::default()
+    })
+}
+
+/// Registers a gauge which value is calculated when a snapshot is taken, e.g. a length of queue.
+/// Replaces a metric registered previously with the same name
+pub fn gauge_fn<F: Fn() -> f64 + Send + Sync + 'static>(h: ModuleHandle, name: &str, help: &str, f: F) {
+    lock_or_recover(&METRICS).entry(h).or_default()
+        .insert(name.to_string(), RegisteredMetric { help: help.to_string(), metric: Metric::GaugeFn(Arc::new(f)) });
+}
+
+/// Returns a histogram of step. The histogram is registered on first call; later calls return the same one
+/// and the buckets are ignored
+pub fn histogram(h: ModuleHandle, name: &str, help: &str, buckets: &[f64]) -> Histogram {
+    let registered = match &register(h, name, help, || Metric::Histogram(Histogram::new(buckets)))[&h][name].metric {
+        Metric::Histogram(hist) => Some(hist.clone()),
+        _ => None,
+    };
+    registered.unwrap_or_else(|| {
+        warn_kind_mismatch(h, name, "histogram");
+        Histogram::new(buckets)
+    })
+}
+
+/// Called after the metrics lock is released, as a logger might read metrics
+fn warn_kind_mismatch(h: ModuleHandle, name: &str, kind: &str) {
+    warn!("Step {}: metric '{}' is already registered as another kind, the {} is not reported", h, name, kind);
+}
+
+/// Removes all metrics of step, e.g. on shutdown
+pub fn unregister_step(h: ModuleHandle) {
+    lock_or_recover(&METRICS).remove(&h);
+}
+
+/// Returns current values of metrics of a single step or all steps
+pub fn snapshot(h: Option<ModuleHandle>) -> Vec<MetricSnapshot> {
+    // Gauge functions are called outside of lock, as they might use metrics too
+    let metrics: Vec<(ModuleHandle, String, String, MetricValueSource)> = {
+        let all = lock_or_recover(&METRICS);
+        let mut steps: Vec<&ModuleHandle> = all.keys().filter(|k| h.is_none() || h == Some(**k)).collect();
+        steps.sort();
+        steps.into_iter()
+            .flat_map(|step| all[step].iter().map(move |(name, m)| (*step, name.clone(), m.help.clone(), m.metric.source())))
+            .collect()
+    };
+    metrics.into_iter()
+        .map(|(step, name, help, source)| MetricSnapshot { step, name, help, value: source.value() })
+        .collect()
+}
+
+/// Something to read the value from
+enum MetricValueSource {
+    Value(MetricValue),
+    Fn(Arc<dyn Fn() -> f64 + Send + Sync>),
+}
+
+impl MetricValueSource {
+    fn value(self) -> MetricValue {
+        match self {
+            MetricValueSource::Value(v) => v,
+            MetricValueSource::Fn(f) => MetricValue::Gauge(f()),
+        }
+    }
+}
+
+impl Metric {
+    fn source(&self) -> MetricValueSource {
+        match self {
+            Metric::Counter(c) => MetricValueSource::Value(MetricValue::Counter(c.get())),
+            Metric::Gauge(g) => MetricValueSource::Value(MetricValue::Gauge(g.get())),
+            Metric::GaugeFn(f) => MetricValueSource::Fn(f.clone()),
+            Metric::Histogram(h) => MetricValueSource::Value(h.value()),
+        }
+    }
+}
+
+/// Renders a snapshot in Prometheus text format. The step handle is added as 'step' label
+/// ```
+/// use torustiq_common::metrics::{histogram, render_prometheus, snapshot};
+/// histogram(12, "latency_seconds", "Processing latency", &[0.1, 1.0]).observe(0.5);
+/// let text = render_prometheus(&snapshot(Some(12)));
+/// assert!(text.contains("# TYPE latency_seconds histogram"));
+/// assert!(text.contains("latency_seconds_bucket{step=\"12\",le=\"1\"} 1"));
+/// assert!(text.contains("latency_seconds_count{step=\"12\"} 1"));
+/// ```
+pub fn render_prometheus(metrics: &[MetricSnapshot]) -> String {
+    let mut by_name: BTreeMap<&str, Vec<&MetricSnapshot>> = BTreeMap::new();
+    for m in metrics {
+        by_name.entry(m.name.as_str()).or_default().push(m);
+    }
+    let mut out = String::new();
+    for (name, samples) in by_name {
+        let first = samples[0];
+        let kind = match first.value.kind() {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, escape_help(&first.help));
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for s in samples {
+            match &s.value {
+                MetricValue::Counter(v) => {
+                    let _ = writeln!(out, "{}{{step=\"{}\"}} {}", name, s.step, v);
+                },
+                MetricValue::Gauge(v) => {
+                    let _ = writeln!(out, "{}{{step=\"{}\"}} {}", name, s.step, format_float(*v));
+                },
+                MetricValue::Histogram { buckets, sum, count } => {
+                    for (le, c) in buckets {
+                        let _ = writeln!(out, "{}_bucket{{step=\"{}\",le=\"{}\"}} {}", name, s.step, format_float(*le), c);
+                    }
+                    let _ = writeln!(out, "{}_bucket{{step=\"{}\",le=\"+Inf\"}} {}", name, s.step, count);
+                    let _ = writeln!(out, "{}_sum{{step=\"{}\"}} {}", name, s.step, format_float(*sum));
+                    let _ = writeln!(out, "{}_count{{step=\"{}\"}} {}", name, s.step, count);
+                },
+            }
+        }
+    }
+    out
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn format_float(v: f64) -> String {
+    if v.is_infinite() {
+        return if v > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() };
+    }
+    if v.is_nan() {
+        return "NaN".to_string();
+    }
+    format!("{}", v)
+}
+
+/// Built-in metrics of record processing
+#[derive(Clone)]
+pub struct ProcessMetrics {
+    pub records_in: Counter,
+    pub bytes_in: Counter,
+    pub errors: Counter,
+    pub duration: Histogram,
+}
+
+impl ProcessMetrics {
+    pub fn register(h: ModuleHandle) -> Self {
+        ProcessMetrics {
+            records_in: counter(h, "torustiq_records_in_total", "Records received by step"),
+            bytes_in: counter(h, "torustiq_bytes_in_total", "Bytes of record content received by step"),
+            errors: counter(h, "torustiq_process_errors_total", "Records which failed to be processed"),
+            duration: histogram(h, "torustiq_process_duration_seconds", "Time spent in the process record function",
+                DEFAULT_DURATION_BUCKETS),
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Typed access to step params and a declarative param schema.
+//! A module registers its schema once; params passed by host are validated against it
+
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Mutex, time::Duration};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    error::{Error, ERROR_CODE_INVALID_PARAM},
+    ffi::{
+        shared::get_param,
+        types::module::ModuleHandle,
+        utils::panic::lock_or_recover,
+    },
+    secret::{is_secret_name, REDACTED},
+};
+
+/// A schema registered by module. If not set, all params are accepted as is
+static PARAM_SCHEMA: Lazy<Mutex<Option<ParamSchema>>> = Lazy::new(|| {
+    Mutex::new(None)
+});
+
+/// A type of param value
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    String,
+    Int,
+    Float,
+    /// true / false, yes / no, on / off, 1 / 0
+    Bool,
+    /// A duration like '500ms', '10s', '1m30s'. A plain number means seconds
+    Duration,
+    /// Comma-separated values
+    List,
+}
+
+/// A description of a single param
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamSpec {
+    pub name: String,
+    pub param_type: ParamType,
+    pub required: bool,
+    /// A value used if param is not set
+    pub default: Option<String>,
+    pub description: String,
+    /// The param contains credentials and must not be logged
+    pub secret: bool,
+}
+
+impl ParamSpec {
+    pub fn new<S: Into<String>>(name: S, param_type: ParamType) -> Self {
+        ParamSpec {
+            name: name.into(),
+            param_type,
+            required: false,
+            default: None,
+            description: String::new(),
+            secret: false,
+        }
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn default_value<S: Into<String>>(mut self, value: S) -> Self {
+        self.default = Some(value.into());
+        self
+    }
+
+    pub fn description<S: Into<String>>(mut self, description: S) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    pub fn secret(mut self) -> Self {
+        self.secret = true;
+        self
+    }
+
+    /// Checks if the param is marked as secret or its name looks like a name of secret
+    pub fn is_secret(&self) -> bool {
+        self.secret || is_secret_name(&self.name)
+    }
+
+    /// Checks if the value can be converted to param type. Values of secret params are redacted in errors
+    /// ```
+    /// use torustiq_common::params::{ParamSpec, ParamType};
+    /// let e = ParamSpec::new("api_key", ParamType::Int).validate("s3cr3t").unwrap_err();
+    /// assert!(!e.message.contains("s3cr3t"));
+    /// ```
+    pub fn validate(&self, value: &str) -> Result<(), Error> {
+        let result = match self.param_type {
+            ParamType::String | ParamType::List => Ok(()),
+            ParamType::Int => value.trim().parse::<i64>().map(|_| ()).map_err(|e| e.to_string()),
+            ParamType::Float => value.trim().parse::<f64>().map(|_| ()).map_err(|e| e.to_string()),
+            ParamType::Bool => parse_bool(value).map(|_| ()),
+            ParamType::Duration => parse_duration(value).map(|_| ()),
+        };
+        let shown = if self.is_secret() { REDACTED } else { value };
+        result.map_err(|msg| invalid_value_error(&self.name, shown, msg))
+    }
+}
+
+/// A set of params accepted by module
+/// ```
+/// use torustiq_common::params::{ParamSchema, ParamSpec, ParamType};
+/// let schema = ParamSchema::new()
+///     .param(ParamSpec::new("batch_size", ParamType::Int).default_value("100"))
+///     .param(ParamSpec::new("url", ParamType::String).required());
+/// assert!(schema.validate_param("batch_size", "10").is_ok());
+/// assert_eq!(schema.validate_param("batch_size", "ten").unwrap_err().param_name, Some("batch_size".to_string()));
+/// assert!(schema.validate_param("unknown", "1").is_err());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParamSchema {
+    pub params: Vec<ParamSpec>,
+    /// Accept params which are not described in schema
+    pub allow_unknown: bool,
+}
+
+impl ParamSchema {
+    pub fn new() -> Self {
+        ParamSchema::default()
+    }
+
+    pub fn param(mut self, spec: ParamSpec) -> Self {
+        self.params.push(spec);
+        self
+    }
+
+    pub fn allow_unknown(mut self, allow: bool) -> Self {
+        self.allow_unknown = allow;
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ParamSpec> {
+        self.params.iter().find(|p| p.name == name)
+    }
+
+    /// Checks if the param is known and its value is valid
+    pub fn validate_param(&self, name: &str, value: &str) -> Result<(), Error> {
+        match self.get(name) {
+            Some(spec) => spec.validate(value),
+            None if self.allow_unknown => Ok(()),
+            None => Err(Error::config(format!("Unknown param: '{}'", name))
+                .with_param(name)
+                .with_code(ERROR_CODE_INVALID_PARAM)),
+        }
+    }
+
+    /// Validates a complete set of params, e.g. from a pipeline definition file
+    pub fn validate_params(&self, params: &HashMap<String, String>) -> Result<(), Error> {
+        params.iter().try_for_each(|(k, v)| self.validate_param(k, v))?;
+        match self.params.iter().find(|p| p.required && p.default.is_none() && !params.contains_key(&p.name)) {
+            Some(p) => Err(missing_param_error(&p.name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Params handled by this library itself. They are accepted by every schema
+pub fn library_params() -> Vec<ParamSpec> {
+    vec![
+        ParamSpec::new(crate::dead_letter::PARAM_DEAD_LETTER_FILE, ParamType::String)
+            .description("A file where records which failed processing are appended as JSON lines"),
+        #[cfg(feature="pipeline_queue")]
+        ParamSpec::new(crate::pipeline::async_process::PARAM_QUEUE_CAPACITY, ParamType::Int)
+            .description("Maximum number of records waiting in step queue. The queue is unbounded if not set"),
+        #[cfg(feature="pipeline_queue")]
+        ParamSpec::new(crate::pipeline::worker_pool::PARAM_WORKERS, ParamType::Int)
+            .default_value("1")
+            .description("Number of workers processing the step queue in parallel, if module uses a worker pool"),
+        #[cfg(feature="pipeline_queue")]
+        ParamSpec::new(crate::pipeline::worker_pool::PARAM_ORDERING_KEY, ParamType::String)
+            .description("A metadata key. Records with the same value of key are processed in order by the same worker"),
+    ]
+}
+
+/// Adds params handled by this library to schema
+pub fn with_library_params(mut schema: ParamSchema) -> ParamSchema {
+    for spec in library_params() {
+        if schema.get(&spec.name).is_none() {
+            schema.params.push(spec);
+        }
+    }
+    schema
+}
+
+/// Registers the params accepted by module. Library params are added automatically
+pub fn register_param_schema(schema: ParamSchema) {
+    *lock_or_recover(&PARAM_SCHEMA) = Some(with_library_params(schema));
+}
+
+/// Returns the schema registered by module, e.g. for documentation or host-side validation
+pub fn get_param_schema() -> Option<ParamSchema> {
+    lock_or_recover(&PARAM_SCHEMA).clone()
+}
+
+/// Checks if the param is secret: it's marked as secret in schema or its name looks like a name of secret
+pub fn is_secret_param(name: &str) -> bool {
+    let in_schema = lock_or_recover(&PARAM_SCHEMA).as_ref()
+        .and_then(|s| s.get(name))
+        .map(|p| p.secret)
+        .unwrap_or(false);
+    in_schema || is_secret_name(name)
+}
+
+/// Validates a param against the registered schema. Any param is valid if there is no schema
+pub fn validate_param(name: &str, value: &str) -> Result<(), Error> {
+    match lock_or_recover(&PARAM_SCHEMA).as_ref() {
+        Some(schema) => schema.validate_param(name, value),
+        None => Ok(()),
+    }
+}
+
+/// Checks if all required params of step are set
+pub fn validate_required_params(h: ModuleHandle) -> Result<(), Error> {
+    let schema = match get_param_schema() {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    match schema.params.iter().find(|p| p.required && p.default.is_none() && get_param(h, &p.name).is_none()) {
+        Some(p) => Err(missing_param_error(&p.name)),
+        None => Ok(()),
+    }
+}
+
+fn missing_param_error(name: &str) -> Error {
+    Error::config(format!("Required param is not set: '{}'", name))
+        .with_param(name)
+        .with_code(ERROR_CODE_INVALID_PARAM)
+}
+
+/// Returns a raw param value, or a default value from schema if the param is not set
+fn get_param_or_default(h: ModuleHandle, name: &str) -> Option<String> {
+    get_param(h, name).or_else(|| {
+        lock_or_recover(&PARAM_SCHEMA).as_ref()
+            .and_then(|s| s.get(name))
+            .and_then(|p| p.default.clone())
+    })
+}
+
+fn invalid_value_error<E: Display>(name: &str, value: &str, e: E) -> Error {
+    Error::config(format!("Invalid value of param '{}': '{}' ({})", name, value, e))
+        .with_param(name)
+        .with_code(ERROR_CODE_INVALID_PARAM)
+}
+
+fn parse_param<T>(h: ModuleHandle, name: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<Option<T>, Error> {
+    match get_param_or_default(h, name) {
+        Some(v) => parse(&v).map(Some).map_err(|e| {
+            let shown = if is_secret_param(name) { REDACTED } else { v.as_str() };
+            invalid_value_error(name, shown, e)
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Returns a param converted to the requested type. None if param is not set and has no default value
+/// ```
+/// use torustiq_common::{ffi::shared::set_param, params::get_param_as};
+/// set_param(5, "port", "8080").unwrap();
+/// assert_eq!(get_param_as::<u16>(5, "port").unwrap(), Some(8080));
+/// assert!(get_param_as::<u8>(5, "port").is_err());
+/// assert_eq!(get_param_as::<u16>(5, "missing").unwrap(), None);
+/// ```
+pub fn get_param_as<T: FromStr>(h: ModuleHandle, name: &str) -> Result<Option<T>, Error> where T::Err: Display {
+    parse_param(h, name, |v| v.trim().parse::<T>().map_err(|e| e.to_string()))
+}
+
+/// Same as `get_param_as`, but a missing param is an error
+pub fn get_required_param<T: FromStr>(h: ModuleHandle, name: &str) -> Result<T, Error> where T::Err: Display {
+    get_param_as(h, name)?.ok_or_else(|| missing_param_error(name))
+}
+
+/// Same as `get_param_as`, but returns the provided value if param is not set
+pub fn get_param_or<T: FromStr>(h: ModuleHandle, name: &str, default: T) -> Result<T, Error> where T::Err: Display {
+    Ok(get_param_as(h, name)?.unwrap_or(default))
+}
+
+pub fn get_bool(h: ModuleHandle, name: &str) -> Result<Option<bool>, Error> {
+    parse_param(h, name, parse_bool)
+}
+
+pub fn get_duration(h: ModuleHandle, name: &str) -> Result<Option<Duration>, Error> {
+    parse_param(h, name, parse_duration)
+}
+
+pub fn get_list(h: ModuleHandle, name: &str) -> Result<Option<Vec<String>>, Error> {
+    parse_param(h, name, |v| Ok(parse_list(v)))
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err("expected a boolean value".to_string()),
+    }
+}
+
+/// Parses a duration: a sequence of numbers with units (ms, s, m, h, d). A plain number means seconds
+/// ```
+/// use std::time::Duration;
+/// use torustiq_common::params::parse_duration;
+/// assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+/// assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+/// assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+/// assert!(parse_duration("5 parsecs").is_err());
+/// ```
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("the value is empty".to_string());
+    }
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| format!("expected a number in '{}'", rest))?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            u => return Err(format!("unknown unit of duration: '{}'", u)),
+        };
+        rest = &rest[unit_len..];
+        let number = u32::try_from(number).map_err(|_| "the number is too large".to_string())?;
+        total = unit.checked_mul(number)
+            .and_then(|d| total.checked_add(d))
+            .ok_or_else(|| "the duration is too large".to_string())?;
+    }
+    Ok(total)
+}
+
+/// Splits comma-separated values. Values are trimmed; empty ones are skipped
+pub fn parse_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
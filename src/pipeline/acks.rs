@@ -0,0 +1,135 @@
+//! Tracking of in-flight records for at-least-once delivery.
+//! A source step assigns an identifier to each record before emitting it. Destination steps report
+//! the delivery outcome via host, and the source commits its position (e.g. Kafka offsets)
+//! only up to the records which are acknowledged.
+//! NB: if a record is sent to several steps, host must report the outcome once all of them are done
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::ffi::{
+    types::module::{ModuleHandle, Record, RecordAckStatus, RecordId},
+    utils::panic::lock_or_recover,
+};
+
+/// In-flight records per source step
+static IN_FLIGHT: Lazy<Mutex<HashMap<ModuleHandle, InFlightRecords>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+#[derive(Default)]
+struct InFlightRecords {
+    /// The last assigned sequence number
+    last_seq: u64,
+    /// Records which are neither acknowledged nor rejected yet, or rejected ones waiting for redelivery
+    pending: BTreeSet<u64>,
+    /// Rejected records which should be delivered again
+    nacked: BTreeSet<u64>,
+}
+
+/// Assigns an identifier to record and marks it as in-flight.
+/// A record which already has an in-flight identifier of this step (e.g. a redelivered one) keeps it
+/// ```
+/// use std::collections::HashMap;
+/// use torustiq_common::ffi::types::module::{Record, RecordAckStatus};
+/// use torustiq_common::pipeline::acks::*;
+///
+/// let mut records: Vec<Record> = (0..3).map(|_| Record::from_std_types(vec![], HashMap::new())).collect();
+/// let ids: Vec<_> = records.iter_mut().map(|r| track_record(7, r)).collect();
+/// on_record_ack(7, ids[0], RecordAckStatus::Ack);
+/// on_record_ack(7, ids[2], RecordAckStatus::Ack);
+/// // The second record is still in flight, so only the first one can be committed
+/// assert_eq!(acked_watermark(7), ids[0].seq);
+/// on_record_ack(7, ids[1], RecordAckStatus::Nack);
+/// assert_eq!(take_nacked(7), vec![ids[1]]);
+/// on_record_ack(7, ids[1], RecordAckStatus::Ack);
+/// assert_eq!(acked_watermark(7), ids[2].seq);
+/// records.iter_mut().for_each(|r| r.free_contents());
+/// ```
+pub fn track_record(h: ModuleHandle, r: &mut Record) -> RecordId {
+    let mut steps = lock_or_recover(&IN_FLIGHT);
+    let step = steps.entry(h).or_default();
+    if r.id.source == h && step.pending.contains(&r.id.seq) {
+        return r.id;
+    }
+    step.last_seq += 1;
+    step.pending.insert(step.last_seq);
+    r.id = RecordId { source: h, seq: step.last_seq };
+    r.id
+}
+
+/// Registers the delivery outcome of record produced by the step.
+/// Returns false if the record is not in flight, e.g. it's already acknowledged
+pub fn on_record_ack(h: ModuleHandle, id: RecordId, status: RecordAckStatus) -> bool {
+    if id.source != h {
+        warn!("Step {} received an acknowledgement of record {:?} produced by another step", h, id);
+        return false;
+    }
+    let mut steps = lock_or_recover(&IN_FLIGHT);
+    let step = match steps.get_mut(&h) {
+        Some(s) => s,
+        None => return false,
+    };
+    if !step.pending.contains(&id.seq) {
+        return false;
+    }
+    match status {
+        RecordAckStatus::Ack => {
+            step.pending.remove(&id.seq);
+            step.nacked.remove(&id.seq);
+        },
+        // A rejected record stays in flight until it's delivered again
+        RecordAckStatus::Nack => {
+            step.nacked.insert(id.seq);
+        },
+    }
+    true
+}
+
+/// Returns the highest sequence number such that all records up to it are acknowledged.
+/// Zero means no records can be committed yet
+pub fn acked_watermark(h: ModuleHandle) -> u64 {
+    let steps = lock_or_recover(&IN_FLIGHT);
+    match steps.get(&h) {
+        Some(step) => match step.pending.first() {
+            Some(seq) => seq - 1,
+            None => step.last_seq,
+        },
+        None => 0,
+    }
+}
+
+/// Returns rejected records which should be delivered again. Each record is returned once per rejection
+pub fn take_nacked(h: ModuleHandle) -> Vec<RecordId> {
+    let mut steps = lock_or_recover(&IN_FLIGHT);
+    match steps.get_mut(&h) {
+        Some(step) => std::mem::take(&mut step.nacked)
+            .into_iter()
+            .map(|seq| RecordId { source: h, seq })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub fn is_in_flight(id: RecordId) -> bool {
+    match lock_or_recover(&IN_FLIGHT).get(&id.source) {
+        Some(step) => step.pending.contains(&id.seq),
+        None => false,
+    }
+}
+
+/// Returns the number of records which are not acknowledged yet
+pub fn in_flight_count(h: ModuleHandle) -> usize {
+    match lock_or_recover(&IN_FLIGHT).get(&h) {
+        Some(step) => step.pending.len(),
+        None => 0,
+    }
+}
+
+/// Forgets all in-flight records of step, e.g. on shutdown
+pub fn reset_tracking(h: ModuleHandle) {
+    lock_or_recover(&IN_FLIGHT).remove(&h);
+}
@@ -1,38 +1,449 @@
-use std::{collections::HashMap, sync::{mpsc::{Receiver, Sender, channel}, Mutex}};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, sync_channel, Receiver, RecvError, RecvTimeoutError, Sender, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use once_cell::sync::Lazy;
-use crate::ffi::types::module::{ModuleHandle, ModulePipelineProcessRecordFnResult, Record};
+use crate::error::{Error, ERROR_CODE_INVALID_PARAM, ERROR_CODE_QUEUE_CLOSED, ERROR_CODE_STEP_POISONED};
+use crate::lifecycle::{check_accepts_records, complete_drain, reset_step_state};
+use crate::logging::enter_step;
+use crate::metrics::{gauge_fn, ProcessMetrics};
+use crate::trace::Span;
+use crate::record::OwnedRecord;
+use crate::ffi::{
+    shared::{get_param, get_step_poison_reason},
+    types::{
+        collections::Array,
+        error::ModuleError,
+        module::{ModuleHandle, ModulePipelineProcessRecordFnResult, ModulePipelineProcessRecordsFnResult, Record},
+        std_types::Uint,
+    },
+    utils::panic::{catch_panic, lock, lock_or_recover},
+};
 
-pub static RECORD_SENDERS: Lazy<Mutex<HashMap<ModuleHandle, Sender<Record>>>> = Lazy::new(|| {
+/// A step param which limits the number of records buffered in step queue.
+/// If not set, the queue is unbounded
+pub const PARAM_QUEUE_CAPACITY: &str = "queue_capacity";
+
+pub static RECORD_SENDERS: Lazy<Mutex<HashMap<ModuleHandle, RecordSender>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+pub static RECORD_RECEIVERS: Lazy<Mutex<HashMap<ModuleHandle, RecordReceiver>>> = Lazy::new(|| {
     Mutex::new(HashMap::new())
 });
 
-pub static RECORD_RECEIVERS: Lazy<Mutex<HashMap<ModuleHandle, Receiver<Record>>>> = Lazy::new(|| {
+static QUEUE_COUNTERS: Lazy<Mutex<HashMap<ModuleHandle, Arc<QueueCounters>>>> = Lazy::new(|| {
     Mutex::new(HashMap::new())
 });
 
+/// Queue statistics of step
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    /// Number of records waiting in queue
+    pub depth: usize,
+    /// Maximum number of records in queue. None if queue is unbounded
+    pub capacity: Option<usize>,
+    /// Total number of records accepted into queue
+    pub sent: u64,
+    /// Total number of records rejected because the queue was full
+    pub rejected: u64,
+}
+
+struct QueueCounters {
+    capacity: Option<usize>,
+    depth: AtomicUsize,
+    sent: AtomicU64,
+    rejected: AtomicU64,
+    metrics: ProcessMetrics,
+}
+
+/// A sending side of step queue
+pub enum RecordSender {
+    Unbounded(Sender<Record>),
+    Bounded(SyncSender<Record>),
+}
+
+impl RecordSender {
+    /// Sends a record without blocking. Bounded queues return TrySendError::Full if capacity is reached
+    pub fn try_send(&self, r: Record) -> Result<(), TrySendError<Record>> {
+        match self {
+            RecordSender::Unbounded(s) => s.send(r).map_err(|e| TrySendError::Disconnected(e.0)),
+            RecordSender::Bounded(s) => s.try_send(r),
+        }
+    }
+}
+
+/// A receiving side of step queue. Keeps track of queue depth.
+/// If the step is draining, the drain is completed once the closed queue is found empty
+pub struct RecordReceiver {
+    handle: ModuleHandle,
+    receiver: Receiver<Record>,
+    counters: Arc<QueueCounters>,
+}
+
+impl RecordReceiver {
+    fn on_received(&self, r: Record) -> Record {
+        self.counters.depth.fetch_sub(1, Ordering::Relaxed);
+        r
+    }
+
+    /// The queue is closed and empty, so all records are passed to module
+    fn on_disconnected(&self) {
+        complete_drain(self.handle);
+    }
+
+    /// Same as `recv`, but doesn't complete the drain of step. Used when records are forwarded
+    /// to other buffers or workers, which complete the drain once they're done
+    pub(crate) fn recv_for_forwarding(&self) -> Result<Record, RecvError> {
+        self.receiver.recv().map(|r| self.on_received(r))
+    }
+
+    /// Same as `recv_timeout`, but doesn't complete the drain of step
+    pub(crate) fn recv_timeout_for_forwarding(&self, timeout: Duration) -> Result<Record, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout).map(|r| self.on_received(r))
+    }
+
+    /// Blocks until a record is available or all senders are dropped
+    pub fn recv(&self) -> Result<Record, RecvError> {
+        self.receiver.recv()
+            .map(|r| self.on_received(r))
+            .inspect_err(|_| self.on_disconnected())
+    }
+
+    pub fn try_recv(&self) -> Result<Record, TryRecvError> {
+        self.receiver.try_recv()
+            .map(|r| self.on_received(r))
+            .inspect_err(|e| if *e == TryRecvError::Disconnected { self.on_disconnected() })
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Record, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+            .map(|r| self.on_received(r))
+            .inspect_err(|e| if *e == RecvTimeoutError::Disconnected { self.on_disconnected() })
+    }
+
+    /// Same as `recv`, but takes ownership of record, so it's freed automatically
+    pub fn recv_owned(&self) -> Result<OwnedRecord, RecvError> {
+        // Records in queue are passed by host for this step only
+        self.recv().map(|r| unsafe { OwnedRecord::from_raw(r) })
+    }
+
+    /// Receives up to 'max_size' records. Waits for records at most 'max_wait'
+    /// and returns the collected ones, so the batch might be empty.
+    /// Returns an error only if all senders are dropped and the queue is empty
+    /// ```
+    /// use std::{collections::HashMap, time::Duration};
+    /// use torustiq_common::ffi::types::{collections::Array, module::{ModulePipelineProcessRecordsFnResult, Record}};
+    /// use torustiq_common::pipeline::async_process::*;
+    ///
+    /// create_bounded_sender_and_receiver(2, 2);
+    /// let records: Vec<Record> = (0..3).map(|i| Record::from_std_types(vec![i], HashMap::new())).collect();
+    /// let mut batch = Array::from_vec(records);
+    /// let result = torustiq_module_pipeline_process_records(2, batch);
+    /// assert!(matches!(result, ModulePipelineProcessRecordsFnResult::ErrBusy(2, 2)));
+    /// // The last record is not consumed and still belongs to caller
+    /// let mut rest = batch.as_slice()[2];
+    /// rest.free_contents();
+    /// batch.free_contents();
+    ///
+    /// let receiver = get_receiver_owned(2).unwrap();
+    /// let received = receiver.recv_batch_owned(10, Duration::from_millis(10)).unwrap();
+    /// assert_eq!(received.len(), 2);
+    /// assert_eq!(received[1].content(), &[1]);
+    /// ```
+    pub fn recv_batch(&self, max_size: usize, max_wait: Duration) -> Result<Vec<Record>, RecvError> {
+        let deadline = Instant::now() + max_wait;
+        let mut batch = Vec::with_capacity(max_size);
+        while batch.len() < max_size {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(timeout) {
+                Ok(r) => batch.push(self.on_received(r)),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) if batch.is_empty() => {
+                    self.on_disconnected();
+                    return Err(RecvError);
+                },
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Same as `recv_batch`, but takes ownership of records, so they're freed automatically
+    pub fn recv_batch_owned(&self, max_size: usize, max_wait: Duration) -> Result<Vec<OwnedRecord>, RecvError> {
+        self.recv_batch(max_size, max_wait)
+            .map(|b| b.into_iter().map(|r| unsafe { OwnedRecord::from_raw(r) }).collect())
+    }
+
+    /// Returns an iterator which blocks waiting for records until all senders are dropped
+    pub fn iter(&self) -> impl Iterator<Item = Record> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+}
+
+/// Puts a record into the step queue. A bounded queue which is full makes this function return 'busy' status.
+/// If the record is traced, an 'enqueue' span measures the time of putting it into the queue.
+/// The function is exported with the 'export_fn__pipeline_process_record' or 'pipeline_module_async_process' feature
+/// ```
+/// use std::collections::HashMap;
+/// use torustiq_common::ffi::types::module::{ModulePipelineProcessRecordFnResult, Record};
+/// use torustiq_common::pipeline::async_process::*;
+///
+/// create_bounded_sender_and_receiver(1, 1);
+/// let record = || Record::from_std_types(vec![1, 2, 3], HashMap::new());
+/// assert!(matches!(torustiq_module_pipeline_process_record(1, record()), ModulePipelineProcessRecordFnResult::Ok(true)));
+/// assert!(matches!(torustiq_module_pipeline_process_record(1, record()), ModulePipelineProcessRecordFnResult::ErrBusy(1, false)));
+/// let stats = get_queue_stats(1).unwrap();
+/// assert_eq!((stats.depth, stats.capacity, stats.rejected), (1, Some(1), 1));
+/// ```
+#[cfg_attr(feature="export_fn__pipeline_process_record", no_mangle)]
+pub extern "C" fn torustiq_module_pipeline_process_record(module_handle: ModuleHandle, in_record: Record) -> ModulePipelineProcessRecordFnResult {
+    match catch_panic("torustiq_module_pipeline_process_record", Some(module_handle),
+        || process_record(module_handle, in_record)) {
+        Ok(r) => r,
+        Err(e) => ModulePipelineProcessRecordFnResult::ErrMisc(e.into(), false),
+    }
+}
+
+fn process_record(module_handle: ModuleHandle, in_record: Record) -> ModulePipelineProcessRecordFnResult {
+    let _scope = enter_step(module_handle);
+    let counters = get_queue_counters(module_handle);
+    // The span measures only putting the record into the queue; processing is traced by the consumer.
+    // The record is not modified, as it still belongs to host if the queue is full,
+    // so the span context is not injected and the next spans continue the span of previous step
+    let mut span = Span::start_for_record("enqueue", module_handle, &in_record);
+    let started = Instant::now();
+    let result = enqueue_record(module_handle, counters.as_deref(), in_record);
+    if let Some(c) = &counters {
+        c.metrics.duration.observe_duration(started.elapsed());
+        if let ModulePipelineProcessRecordFnResult::ErrMisc(..) = result {
+            c.metrics.errors.inc();
+        }
+    }
+    if let (Some(s), ModulePipelineProcessRecordFnResult::ErrMisc(e, _)) = (&mut span, &result) {
+        s.set_error(e.message());
+    }
+    result
+}
 
-#[no_mangle]
-extern "C" fn torustiq_module_pipeline_process_record(module_handle: ModuleHandle, in_record: Record) -> ModulePipelineProcessRecordFnResult {
-    let mutex = RECORD_SENDERS.lock().unwrap();
+fn enqueue_record(module_handle: ModuleHandle, counters: Option<&QueueCounters>, in_record: Record) -> ModulePipelineProcessRecordFnResult {
+    if let Some(reason) = get_step_poison_reason(module_handle) {
+        return ModulePipelineProcessRecordFnResult::ErrMisc(poisoned_step_error(module_handle, reason), false);
+    }
+    match check_accepts_records(module_handle) {
+        Ok(true) => {},
+        Ok(false) => return ModulePipelineProcessRecordFnResult::ErrBusy(module_handle, false),
+        Err(e) => return ModulePipelineProcessRecordFnResult::ErrMisc(e.into(), false),
+    }
+    let mutex = match lock(&RECORD_SENDERS, "record senders") {
+        Ok(m) => m,
+        Err(e) => return ModulePipelineProcessRecordFnResult::ErrMisc(e.into(), false),
+    };
     let sender = match mutex.get(&module_handle) {
         Some(s) => s,
         None => return ModulePipelineProcessRecordFnResult::ErrWrongModuleHandle(module_handle, false),
     };
-    sender.send(in_record).unwrap();
-    ModulePipelineProcessRecordFnResult::Ok(true)
+    match send_record(sender, counters, in_record) {
+        Ok(_) => ModulePipelineProcessRecordFnResult::Ok(true),
+        Err(TrySendError::Full(_)) => ModulePipelineProcessRecordFnResult::ErrBusy(module_handle, false),
+        Err(TrySendError::Disconnected(_)) => ModulePipelineProcessRecordFnResult::ErrMisc(
+            queue_closed_error(module_handle), false),
+    }
+}
+
+/// Puts a batch of records into the step queue. The queue lock is acquired once per batch.
+/// If the queue becomes full, the rest of batch is not consumed and stays owned by caller
+/// The function is exported with the 'export_fn__pipeline_process_records' or 'pipeline_module_async_process' feature
+#[cfg_attr(feature="export_fn__pipeline_process_records", no_mangle)]
+pub extern "C" fn torustiq_module_pipeline_process_records(module_handle: ModuleHandle, in_records: Array<Record>) -> ModulePipelineProcessRecordsFnResult {
+    // Counted outside of the closure, so records which are already in the queue are reported after panic
+    let consumed = Cell::new(0);
+    match catch_panic("torustiq_module_pipeline_process_records", Some(module_handle),
+        || process_records(module_handle, in_records, &consumed)) {
+        Ok(r) => r,
+        Err(e) => ModulePipelineProcessRecordsFnResult::ErrMisc(e.into(), consumed.get()),
+    }
+}
+
+fn process_records(module_handle: ModuleHandle, in_records: Array<Record>, consumed: &Cell<Uint>) -> ModulePipelineProcessRecordsFnResult {
+    let _scope = enter_step(module_handle);
+    let counters = get_queue_counters(module_handle);
+    // Like in 'process_record', the spans measure only the enqueue. Records are put into the queue together,
+    // so each span covers the enqueue of the whole batch
+    let _spans: Vec<Span> = in_records.as_slice().iter()
+        .filter_map(|r| Span::start_for_record("enqueue", module_handle, r))
+        .map(|mut s| {
+            s.set_attribute("batch_size", in_records.len.to_string());
+            s
+        })
+        .collect();
+    let started = Instant::now();
+    let result = enqueue_records(module_handle, counters.as_deref(), in_records, consumed);
+    if let Some(c) = &counters {
+        c.metrics.duration.observe_duration(started.elapsed());
+        if let ModulePipelineProcessRecordsFnResult::ErrMisc(..) = result {
+            c.metrics.errors.inc();
+        }
+    }
+    result
+}
+
+fn enqueue_records(module_handle: ModuleHandle, counters: Option<&QueueCounters>, in_records: Array<Record>,
+    consumed: &Cell<Uint>) -> ModulePipelineProcessRecordsFnResult
+{
+    if let Some(reason) = get_step_poison_reason(module_handle) {
+        return ModulePipelineProcessRecordsFnResult::ErrMisc(poisoned_step_error(module_handle, reason), 0);
+    }
+    match check_accepts_records(module_handle) {
+        Ok(true) => {},
+        Ok(false) => return ModulePipelineProcessRecordsFnResult::ErrBusy(module_handle, 0),
+        Err(e) => return ModulePipelineProcessRecordsFnResult::ErrMisc(e.into(), 0),
+    }
+    let mutex = match lock(&RECORD_SENDERS, "record senders") {
+        Ok(m) => m,
+        Err(e) => return ModulePipelineProcessRecordsFnResult::ErrMisc(e.into(), 0),
+    };
+    let sender = match mutex.get(&module_handle) {
+        Some(s) => s,
+        None => return ModulePipelineProcessRecordsFnResult::ErrWrongModuleHandle(module_handle, 0),
+    };
+    for r in in_records.as_slice() {
+        match send_record(sender, counters, *r) {
+            Ok(_) => consumed.set(consumed.get() + 1),
+            Err(TrySendError::Full(_)) => return ModulePipelineProcessRecordsFnResult::ErrBusy(module_handle, consumed.get()),
+            Err(TrySendError::Disconnected(_)) => return ModulePipelineProcessRecordsFnResult::ErrMisc(
+                queue_closed_error(module_handle), consumed.get()),
+        }
+    }
+    ModulePipelineProcessRecordsFnResult::Ok(consumed.get())
+}
+
+fn poisoned_step_error(module_handle: ModuleHandle, reason: String) -> ModuleError {
+    Error::internal(format!("Step {} is poisoned: {}", module_handle, reason))
+        .with_code(ERROR_CODE_STEP_POISONED)
+        .into()
+}
+
+fn queue_closed_error(module_handle: ModuleHandle) -> ModuleError {
+    Error::internal(format!("The queue of step {} is closed", module_handle))
+        .with_code(ERROR_CODE_QUEUE_CLOSED)
+        .into()
+}
+
+/// Sends a record and updates queue counters and metrics
+fn send_record(sender: &RecordSender, counters: Option<&QueueCounters>, r: Record) -> Result<(), TrySendError<Record>> {
+    // Depth is increased before sending, as the receiver might take the record immediately
+    if let Some(c) = counters {
+        c.depth.fetch_add(1, Ordering::Relaxed);
+    }
+    let bytes = r.content.len as u64;
+    let result = sender.try_send(r);
+    if let Some(c) = counters {
+        match &result {
+            Ok(_) => {
+                c.sent.fetch_add(1, Ordering::Relaxed);
+                c.metrics.records_in.inc();
+                c.metrics.bytes_in.add(bytes);
+            },
+            Err(e) => {
+                c.depth.fetch_sub(1, Ordering::Relaxed);
+                if let TrySendError::Full(_) = e {
+                    c.rejected.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+        }
+    }
+    result
+}
+
+fn get_queue_counters(handle: ModuleHandle) -> Option<Arc<QueueCounters>> {
+    lock_or_recover(&QUEUE_COUNTERS).get(&handle).cloned()
+}
+
+/// Closes the step queue, so no records are accepted anymore. Records in queue are still available to receiver.
+/// Returns false if step has no queue
+/// ```
+/// use std::collections::HashMap;
+/// use torustiq_common::ffi::types::module::{ModulePipelineProcessRecordFnResult, Record, StepTerminationStatus};
+/// use torustiq_common::lifecycle::{begin_drain, get_step_state, StepState};
+/// use torustiq_common::pipeline::async_process::*;
+///
+/// create_sender_and_receiver(5).unwrap();
+/// let receiver = get_receiver_owned(5).unwrap();
+/// torustiq_module_pipeline_process_record(5, Record::from_std_types(vec![1], HashMap::new()));
+/// begin_drain(5).unwrap();
+/// assert!(close_queue(5));
+/// let mut rejected = Record::from_std_types(vec![2], HashMap::new());
+/// assert!(matches!(torustiq_module_pipeline_process_record(5, rejected), ModulePipelineProcessRecordFnResult::ErrMisc(..)));
+/// rejected.free_contents();
+/// // Queued records are still delivered, then the drain is completed
+/// assert_eq!(receiver.recv_owned().unwrap().content(), &[1]);
+/// assert!(receiver.recv().is_err());
+/// assert_eq!(get_step_state(5), StepState::Terminated(StepTerminationStatus::Drained));
+/// ```
+pub fn close_queue(handle: ModuleHandle) -> bool {
+    lock_or_recover(&RECORD_SENDERS).remove(&handle).is_some()
 }
 
 /// Extracts a receiver object from the map and returns it
-pub fn get_receiver_owned(handle: ModuleHandle) -> Option<Receiver<Record>> {
-    match RECORD_RECEIVERS.lock().unwrap().remove(&handle) {
-        Some(r) => Some(r),
-        None => None,
+pub fn get_receiver_owned(handle: ModuleHandle) -> Option<RecordReceiver> {
+    lock_or_recover(&RECORD_RECEIVERS).remove(&handle)
+}
+
+/// Creates a sender and a receiver; stores them inside module maps.
+/// The queue is bounded if the 'queue_capacity' step param is set. Returns an error if the param is invalid
+pub fn create_sender_and_receiver(module_handle: ModuleHandle) -> Result<(), Error> {
+    match get_param(module_handle, PARAM_QUEUE_CAPACITY) {
+        Some(c) => match c.parse::<usize>() {
+            Ok(c) if c > 0 => create_bounded_sender_and_receiver(module_handle, c),
+            _ => return Err(Error::config(format!("The '{}' param must be a positive integer, got '{}'", PARAM_QUEUE_CAPACITY, c))
+                .with_param(PARAM_QUEUE_CAPACITY)
+                .with_code(ERROR_CODE_INVALID_PARAM)),
+        },
+        None => {
+            let (sender, receiver) = channel::<Record>();
+            register_queue(module_handle, RecordSender::Unbounded(sender), receiver, None);
+        },
     }
+    Ok(())
 }
 
-/// Creates a sender and a receiver; stores them inside module maps
-pub fn create_sender_and_receiver(module_handle: ModuleHandle) {
-    let (sender, receiver) = channel::<Record>();
-    RECORD_RECEIVERS.lock().unwrap().insert(module_handle, receiver);
-    RECORD_SENDERS.lock().unwrap().insert(module_handle, sender);
-}
\ No newline at end of file
+/// Creates a queue which holds at most 'capacity' records. If the queue is full,
+/// the process record function returns the 'busy' status and the record is not consumed
+pub fn create_bounded_sender_and_receiver(module_handle: ModuleHandle, capacity: usize) {
+    let (sender, receiver) = sync_channel::<Record>(capacity);
+    register_queue(module_handle, RecordSender::Bounded(sender), receiver, Some(capacity));
+}
+
+fn register_queue(module_handle: ModuleHandle, sender: RecordSender, receiver: Receiver<Record>, capacity: Option<usize>) {
+    let counters = Arc::new(QueueCounters {
+        capacity,
+        depth: AtomicUsize::new(0),
+        sent: AtomicU64::new(0),
+        rejected: AtomicU64::new(0),
+        metrics: ProcessMetrics::register(module_handle),
+    });
+    let depth_counters = counters.clone();
+    gauge_fn(module_handle, "torustiq_queue_depth", "Records waiting in step queue",
+        move || depth_counters.depth.load(Ordering::Relaxed) as f64);
+    lock_or_recover(&QUEUE_COUNTERS).insert(module_handle, counters.clone());
+    lock_or_recover(&RECORD_RECEIVERS).insert(module_handle, RecordReceiver { handle: module_handle, receiver, counters });
+    lock_or_recover(&RECORD_SENDERS).insert(module_handle, sender);
+    reset_step_state(module_handle);
+}
+
+/// Returns queue statistics of step
+pub fn get_queue_stats(module_handle: ModuleHandle) -> Option<QueueStats> {
+    get_queue_counters(module_handle).map(|c| QueueStats {
+        depth: c.depth.load(Ordering::Relaxed),
+        capacity: c.capacity,
+        sent: c.sent.load(Ordering::Relaxed),
+        rejected: c.rejected.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,144 @@
+//! Exponential backoff between retries of failed operations
+
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    error::Error,
+    ffi::{types::module::ModuleHandle, utils::panic::lock_or_recover},
+    params::{get_duration, get_param_as, ParamSpec, ParamType},
+};
+
+/// A step param with the delay before the first retry
+pub const PARAM_RETRY_INITIAL_DELAY: &str = "retry_initial_delay";
+/// A step param with the maximum delay between retries
+pub const PARAM_RETRY_MAX_DELAY: &str = "retry_max_delay";
+/// A step param with the number of retries after which the operation fails. Unlimited if not set
+pub const PARAM_RETRY_MAX_ATTEMPTS: &str = "retry_max_attempts";
+
+/// Params read by `BackoffConfig::from_params`, to be added to the param schema of module
+pub fn backoff_params() -> Vec<ParamSpec> {
+    vec![
+        ParamSpec::new(PARAM_RETRY_INITIAL_DELAY, ParamType::Duration)
+            .description("Delay before the first retry of a failed operation"),
+        ParamSpec::new(PARAM_RETRY_MAX_DELAY, ParamType::Duration)
+            .description("Maximum delay between retries. The delay doubles after each failure"),
+        ParamSpec::new(PARAM_RETRY_MAX_ATTEMPTS, ParamType::Int)
+            .description("Number of retries before the step fails. Retries are unlimited if not set"),
+    ]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackoffConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The delay is multiplied by this factor after each failure
+    pub multiplier: f64,
+    /// None means retrying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            max_attempts: None,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Reads the config from step params. Params which are not set keep default values
+    pub fn from_params(h: ModuleHandle) -> Result<Self, Error> {
+        let default = BackoffConfig::default();
+        Ok(BackoffConfig {
+            initial_delay: get_duration(h, PARAM_RETRY_INITIAL_DELAY)?.unwrap_or(default.initial_delay),
+            max_delay: get_duration(h, PARAM_RETRY_MAX_DELAY)?.unwrap_or(default.max_delay),
+            max_attempts: get_param_as(h, PARAM_RETRY_MAX_ATTEMPTS)?.or(default.max_attempts),
+            ..default
+        })
+    }
+}
+
+/// Calculates delays between retries. Reset it after a successful attempt
+/// ```
+/// use std::time::Duration;
+/// use torustiq_common::pipeline::backoff::{Backoff, BackoffConfig};
+/// let mut b = Backoff::new(BackoffConfig {
+///     initial_delay: Duration::from_millis(100),
+///     max_delay: Duration::from_millis(300),
+///     multiplier: 2.0,
+///     max_attempts: Some(3),
+/// });
+/// assert_eq!(b.next_delay(), Some(Duration::from_millis(100)));
+/// assert_eq!(b.next_delay(), Some(Duration::from_millis(200)));
+/// assert_eq!(b.next_delay(), Some(Duration::from_millis(300)));
+/// assert_eq!(b.next_delay(), None);
+/// b.reset();
+/// assert_eq!(b.next_delay(), Some(Duration::from_millis(100)));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Backoff { config, attempts: 0 }
+    }
+
+    /// Returns the delay before the next retry, or None if no retries are left
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.config.max_attempts.is_some_and(|max| self.attempts >= max) {
+            return None;
+        }
+        let factor = self.config.multiplier.max(1.0).powi(self.attempts.min(i32::MAX as u32) as i32);
+        self.attempts += 1;
+        // Checked in seconds first, as multiplying a duration panics on overflow
+        match self.config.initial_delay.as_secs_f64() * factor < self.config.max_delay.as_secs_f64() {
+            true => Some(self.config.initial_delay.mul_f64(factor)),
+            false => Some(self.config.max_delay),
+        }
+    }
+
+    /// Number of retries since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Interrupts waiting between retries when a runner should stop
+#[derive(Default)]
+pub(crate) struct StopSignal {
+    stopped: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl StopSignal {
+    pub(crate) fn stop(&self) {
+        *lock_or_recover(&self.stopped) = true;
+        self.cond.notify_all();
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        *lock_or_recover(&self.stopped)
+    }
+
+    /// Waits for timeout. Returns true if stopped meanwhile
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let stopped = lock_or_recover(&self.stopped);
+        match self.cond.wait_timeout_while(stopped, timeout, |s| !*s) {
+            Ok((s, _)) => *s,
+            Err(e) => *e.into_inner().0,
+        }
+    }
+}
@@ -1,2 +1,12 @@
-#[cfg(feature="pipeline_module_async_process")]
-pub mod async_process;
\ No newline at end of file
+pub mod acks;
+#[cfg(feature="pipeline_queue")]
+pub mod async_process;
+pub mod backoff;
+pub mod module;
+#[cfg(feature="pipeline_queue")]
+pub mod sink;
+pub mod source;
+#[cfg(feature="tokio")]
+pub mod tokio_runtime;
+#[cfg(feature="pipeline_queue")]
+pub mod worker_pool;
@@ -0,0 +1,627 @@
+//! A safe interface for pipeline modules. Implement `PipelineModule` and call `export_pipeline_module!`
+//! to generate all C ABI functions expected by host.
+//! NB: don't combine it with `export_fn__*` features or `pipeline_module_async_process`, as the same symbols
+//! would be exported twice. Use `pipeline_queue` for the step queue instead
+
+use std::{cell::Cell, collections::BTreeMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{
+    ffi::{
+        shared::{
+            get_step_poison_reason, init_pipeline_lib, set_pipeline_module_configuration, set_step_poisoned,
+        },
+        types::module::{
+            LibInfo, LibInitFnResult, LibPipelineInitArgs, ModuleHandle, ModuleKind, ModulePipelineConfigureArgs, ModulePipelineConfigureFnResult,
+            ModulePipelineProcessRecordFnResult, ModulePipelineProcessRecordsFnResult, PipelineModuleKind, Record,
+            RecordAckStatus, RecordId, StepControlFnResult, StepStartFnResult, StepTerminationStatus,
+        },
+        types::{collections::Array, metrics::{metrics_to_array, MetricSample}, params::LibParamSchema, std_types::Uint},
+        utils::{
+            panic::{catch_panic, lock, lock_or_recover},
+            strings::string_to_cchar,
+        },
+    },
+    dead_letter::remove_dead_letter_handler,
+    error::{Error, ERROR_CODE_STEP_NOT_READY, ERROR_CODE_STEP_POISONED},
+    lifecycle::{
+        begin_drain, check_accepts_records, complete_drain, pause_step, reset_step_state, resume_step,
+        spawn_drain_watchdog, terminate_step,
+    },
+    logging::enter_step,
+    metrics::{snapshot, unregister_step, ProcessMetrics},
+    params::{register_param_schema, validate_required_params, with_library_params, ParamSchema},
+    pipeline::acks::{on_record_ack, reset_tracking},
+    record::OwnedRecord,
+    CURRENT_API_VERSION,
+};
+
+/// An error returned from `PipelineModule::configure`
+pub enum ConfigureError {
+    /// The provided kind (source, transformation, destination) is not supported by module
+    KindNotSupported,
+    /// Module can be used in one step only. Argument is a handle of previously configured step
+    MultipleStepsNotSupported(ModuleHandle),
+    /// Other kind of error
+    Misc(Error),
+}
+
+impl From<Error> for ConfigureError {
+    fn from(value: Error) -> Self {
+        ConfigureError::Misc(value)
+    }
+}
+
+/// A pipeline module implemented in safe Rust. One instance is created per step
+pub trait PipelineModule: Send + 'static {
+    /// Module identifier, e.g. 'stdio'
+    const ID: &'static str;
+    /// Human-readable module name
+    const NAME: &'static str;
+
+    /// Returns the params accepted by module. Params are not validated if schema is not provided
+    fn param_schema() -> Option<ParamSchema> where Self: Sized {
+        None
+    }
+
+    /// Creates an instance for step
+    fn new(handle: ModuleHandle) -> Self where Self: Sized;
+
+    /// Configures the step. Called once before start
+    fn configure(&mut self, kind: PipelineModuleKind) -> Result<(), ConfigureError>;
+
+    /// Starts the step routines. Step params are available at this point
+    fn start(&mut self) -> Result<(), Error>;
+
+    /// Processes a record received from the previous step.
+    /// The record is owned by module from now on, so it's considered consumed even if error is returned
+    fn process(&mut self, record: OwnedRecord) -> Result<(), Error>;
+
+    /// Processes a batch of records. By default records are passed to `process` one by one.
+    /// Like in `process`, all records are considered consumed even if error is returned
+    fn process_batch(&mut self, records: Vec<OwnedRecord>) -> Result<(), Error> {
+        records.into_iter().try_for_each(|r| self.process(r))
+    }
+
+    /// Called when a record produced by this step is acknowledged or rejected downstream.
+    /// The outcome is registered in `pipeline::acks` before this call
+    fn on_ack(&mut self, _id: RecordId, _status: RecordAckStatus) {}
+
+    /// Called when host drains the step. Processes or flushes records buffered inside module.
+    /// The step is shut down afterwards
+    fn drain(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when host shuts the step down
+    fn shutdown(&mut self) {}
+}
+
+/// Returns the library information for module
+pub fn lib_info<M: PipelineModule>() -> LibInfo {
+    LibInfo {
+        api_version: CURRENT_API_VERSION,
+        id: string_to_cchar(M::ID),
+        kind: ModuleKind::Pipeline,
+        name: string_to_cchar(M::NAME),
+    }
+}
+
+/// Returns the library information, or an unsupported API version if a panic occurred,
+/// so host refuses to load the library
+pub fn lib_info_safe<M: PipelineModule>() -> LibInfo {
+    match catch_panic("torustiq_lib_get_info", None, lib_info::<M>) {
+        Ok(i) => i,
+        Err(_) => LibInfo {
+            api_version: 0,
+            id: std::ptr::null(),
+            kind: ModuleKind::Pipeline,
+            name: std::ptr::null(),
+        },
+    }
+}
+
+/// Returns the param schema of module, including params handled by this library
+pub fn param_schema_safe<M: PipelineModule>() -> LibParamSchema {
+    let schema = catch_panic("torustiq_lib_get_param_schema", None, || M::param_schema().map(with_library_params));
+    LibParamSchema::from(schema.ok().flatten().as_ref())
+}
+
+/// Returns current metrics of all steps of library
+pub fn metrics_safe() -> Array<MetricSample> {
+    match catch_panic("torustiq_lib_get_metrics", None, || metrics_to_array(&snapshot(None))) {
+        Ok(arr) => arr,
+        Err(_) => Array::from_vec(Vec::new()),
+    }
+}
+
+/// Registers the param schema of module and initializes the library
+pub fn init_lib<M: PipelineModule>(a: LibPipelineInitArgs) -> LibInitFnResult {
+    if let Some(schema) = M::param_schema() {
+        register_param_schema(schema);
+    }
+    init_pipeline_lib(a)
+}
+
+/// Module instances per step handle. Used by `export_pipeline_module!` macro
+pub struct ModuleSteps<M: PipelineModule> {
+    steps: Mutex<BTreeMap<ModuleHandle, Arc<Step<M>>>>,
+}
+
+/// A module instance and its built-in metrics
+struct Step<M> {
+    module: Mutex<M>,
+    metrics: ProcessMetrics,
+}
+
+impl<M: PipelineModule> Default for ModuleSteps<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: PipelineModule> ModuleSteps<M> {
+    pub const fn new() -> Self {
+        ModuleSteps {
+            steps: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns a step instance. Fails if step is poisoned
+    fn get(&self, h: ModuleHandle) -> Result<Option<Arc<Step<M>>>, Error> {
+        if let Some(reason) = get_step_poison_reason(h) {
+            return Err(Error::internal(format!("Step {} is poisoned: {}", h, reason)).with_code(ERROR_CODE_STEP_POISONED));
+        }
+        Ok(lock_or_recover(&self.steps).get(&h).cloned())
+    }
+
+    /// Runs a method of step instance. A poisoned instance lock poisons the step
+    fn call<R, F: FnOnce(&mut M) -> R>(&self, h: ModuleHandle, module: &Mutex<M>, f: F) -> Result<R, Error> {
+        let mut m = lock(module, "module step").inspect_err(|e| set_step_poisoned(h, e.message.clone()))?;
+        Ok(f(&mut m))
+    }
+
+    pub fn configure(&self, a: ModulePipelineConfigureArgs) -> ModulePipelineConfigureFnResult {
+        let result = catch_panic("torustiq_module_pipeline_configure", Some(a.module_handle), || {
+            let _scope = enter_step(a.module_handle);
+            let h = a.module_handle;
+            let mut module = M::new(h);
+            match module.configure(a.kind.clone()) {
+                Ok(_) => {},
+                Err(ConfigureError::KindNotSupported) => return ModulePipelineConfigureFnResult::ErrorKindNotSupported,
+                Err(ConfigureError::MultipleStepsNotSupported(other)) =>
+                    return ModulePipelineConfigureFnResult::ErrorMultipleStepsNotSupported(other),
+                Err(ConfigureError::Misc(e)) => return ModulePipelineConfigureFnResult::ErrorMisc(e.into()),
+            }
+            set_pipeline_module_configuration(a);
+            reset_step_state(h);
+            lock_or_recover(&self.steps).insert(h, Arc::new(Step {
+                module: Mutex::new(module),
+                metrics: ProcessMetrics::register(h),
+            }));
+            ModulePipelineConfigureFnResult::Ok
+        });
+        match result {
+            Ok(r) => r,
+            Err(e) => ModulePipelineConfigureFnResult::ErrorMisc(e.into()),
+        }
+    }
+
+    pub fn start(&self, h: ModuleHandle) -> StepStartFnResult {
+        let result = catch_panic("torustiq_module_common_start", Some(h), || {
+            let _scope = enter_step(h);
+            let module = self.get(h)?.ok_or_else(||
+                Error::internal(format!("Step {} is not configured", h)).with_code(ERROR_CODE_STEP_NOT_READY))?;
+            validate_required_params(h)?;
+            self.call(h, &module.module, |m| m.start())?
+        });
+        match result.and_then(|r| r) {
+            Ok(_) => StepStartFnResult::Ok,
+            Err(e) => StepStartFnResult::ErrorMisc(e.into()),
+        }
+    }
+
+    /// The record is consumed once it's converted into `OwnedRecord`, so it's reported as consumed
+    /// even if the module panics: the unwind has already freed it
+    /// ```
+    /// use std::collections::HashMap;
+    /// use torustiq_common::error::Error;
+    /// use torustiq_common::ffi::types::module::*;
+    /// use torustiq_common::pipeline::module::{ConfigureError, ModuleSteps, PipelineModule};
+    /// use torustiq_common::record::OwnedRecord;
+    ///
+    /// struct Panicky;
+    ///
+    /// impl PipelineModule for Panicky {
+    ///     const ID: &'static str = "panicky";
+    ///     const NAME: &'static str = "Panicky";
+    ///     fn new(_: ModuleHandle) -> Self { Panicky }
+    ///     fn configure(&mut self, _: PipelineModuleKind) -> Result<(), ConfigureError> { Ok(()) }
+    ///     fn start(&mut self) -> Result<(), Error> { Ok(()) }
+    ///     fn process(&mut self, _: OwnedRecord) -> Result<(), Error> { panic!("boom") }
+    /// }
+    ///
+    /// let steps = ModuleSteps::<Panicky>::new();
+    /// steps.configure(ModulePipelineConfigureArgs { kind: PipelineModuleKind::Transformation, module_handle: 2 });
+    /// let result = steps.process(2, Record::from_std_types(vec![1], HashMap::new()));
+    /// assert!(matches!(result, ModulePipelineProcessRecordFnResult::ErrMisc(_, true)));
+    /// ```
+    pub fn process(&self, h: ModuleHandle, record: Record) -> ModulePipelineProcessRecordFnResult {
+        let consumed = Cell::new(false);
+        let result = catch_panic("torustiq_module_pipeline_process_record", Some(h), || {
+            let _scope = enter_step(h);
+            let step = match self.get(h)? {
+                Some(s) => s,
+                None => return Ok(ModulePipelineProcessRecordFnResult::ErrWrongModuleHandle(h, false)),
+            };
+            if !check_accepts_records(h)? {
+                return Ok(ModulePipelineProcessRecordFnResult::ErrBusy(h, false));
+            }
+            step.metrics.records_in.inc();
+            step.metrics.bytes_in.add(record.content.len as u64);
+            let started = Instant::now();
+            // Host passes the record to this step only, so the step takes ownership.
+            // The record is freed even if the step is not called, so it's consumed in any case
+            let record = unsafe { OwnedRecord::from_raw(record) };
+            consumed.set(true);
+            let result = catch_panic("torustiq_module_pipeline_process_record", Some(h),
+                || self.call(h, &step.module, |m| m.process(record)))
+                .and_then(|r| r)
+                .and_then(|r| r);
+            step.metrics.duration.observe_duration(started.elapsed());
+            Ok(match result {
+                Ok(_) => ModulePipelineProcessRecordFnResult::Ok(true),
+                Err(e) => {
+                    step.metrics.errors.inc();
+                    ModulePipelineProcessRecordFnResult::ErrMisc(e.into(), true)
+                },
+            })
+        });
+        match result.and_then(|r| r) {
+            Ok(r) => r,
+            Err(e) => ModulePipelineProcessRecordFnResult::ErrMisc(e.into(), consumed.get()),
+        }
+    }
+
+    /// Like `process`, all records are reported as consumed once they're converted into `OwnedRecord`
+    pub fn process_batch(&self, h: ModuleHandle, records: Array<Record>) -> ModulePipelineProcessRecordsFnResult {
+        let consumed = Cell::new(0);
+        let result = catch_panic("torustiq_module_pipeline_process_records", Some(h), || {
+            let _scope = enter_step(h);
+            let step = match self.get(h)? {
+                Some(s) => s,
+                None => return Ok(ModulePipelineProcessRecordsFnResult::ErrWrongModuleHandle(h, 0)),
+            };
+            if !check_accepts_records(h)? {
+                return Ok(ModulePipelineProcessRecordsFnResult::ErrBusy(h, 0));
+            }
+            let count = records.len;
+            step.metrics.records_in.add(count as u64);
+            step.metrics.bytes_in.add(records.as_slice().iter().map(|r| r.content.len as u64).sum());
+            let started = Instant::now();
+            // Host passes the records to this step only, so the step takes ownership of all of them
+            let records: Vec<OwnedRecord> = records.as_slice().iter()
+                .map(|r| unsafe { OwnedRecord::from_raw(*r) })
+                .collect();
+            consumed.set(count);
+            let result = catch_panic("torustiq_module_pipeline_process_records", Some(h),
+                || self.call(h, &step.module, |m| m.process_batch(records)))
+                .and_then(|r| r)
+                .and_then(|r| r);
+            step.metrics.duration.observe_duration(started.elapsed());
+            Ok(match result {
+                Ok(_) => ModulePipelineProcessRecordsFnResult::Ok(count),
+                Err(e) => {
+                    step.metrics.errors.inc();
+                    ModulePipelineProcessRecordsFnResult::ErrMisc(e.into(), count)
+                },
+            })
+        });
+        match result.and_then(|r| r) {
+            Ok(r) => r,
+            Err(e) => ModulePipelineProcessRecordsFnResult::ErrMisc(e.into(), consumed.get()),
+        }
+    }
+
+    pub fn ack(&self, h: ModuleHandle, id: RecordId, status: RecordAckStatus) {
+        let _ = catch_panic("torustiq_module_pipeline_record_ack", Some(h), || {
+            let _scope = enter_step(h);
+            if !on_record_ack(h, id, status) {
+                return Ok(());
+            }
+            match self.get(h)? {
+                Some(step) => self.call(h, &step.module, |m| m.on_ack(id, status)),
+                None => Ok(()),
+            }
+        });
+    }
+
+    pub fn shutdown(&self, h: ModuleHandle) {
+        let _ = catch_panic("torustiq_module_common_shutdown", Some(h), || {
+            let _scope = enter_step(h);
+            self.remove(h);
+            terminate_step(h, StepTerminationStatus::Shutdown);
+        });
+    }
+
+    /// Removes the step instance and releases resources of step
+    fn remove(&self, h: ModuleHandle) {
+        let step = lock_or_recover(&self.steps).remove(&h);
+        if let Some(s) = step {
+            // A poisoned step is still shut down, as its state is not used anymore
+            lock_or_recover(&s.module).shutdown();
+        }
+        reset_tracking(h);
+        unregister_step(h);
+        remove_dead_letter_handler(h);
+        #[cfg(feature="tokio")]
+        crate::pipeline::tokio_runtime::shutdown_step(h);
+    }
+
+    pub fn pause(&self, h: ModuleHandle) -> StepControlFnResult {
+        control_result(catch_panic("torustiq_module_common_pause", Some(h), || pause_step(h)))
+    }
+
+    pub fn resume(&self, h: ModuleHandle) -> StepControlFnResult {
+        control_result(catch_panic("torustiq_module_common_resume", Some(h), || resume_step(h)))
+    }
+
+    /// Stops accepting records and drains the step in background. The module is shut down afterwards
+    pub fn drain(&'static self, h: ModuleHandle, timeout_ms: Uint) -> StepControlFnResult {
+        control_result(catch_panic("torustiq_module_common_drain", Some(h), || {
+            begin_drain(h)?;
+            spawn_drain_watchdog(h, Duration::from_millis(timeout_ms as u64));
+            std::thread::spawn(move || {
+                let _ = catch_panic("torustiq_module_common_drain", Some(h), || {
+                    let _scope = enter_step(h);
+                    // Waits for the record which is being processed, if any
+                    let result = match self.get(h) {
+                        Ok(Some(step)) => self.call(h, &step.module, |m| m.drain()).and_then(|r| r),
+                        Ok(None) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    self.remove(h);
+                    match result {
+                        Ok(_) => {
+                            complete_drain(h);
+                        },
+                        Err(e) => {
+                            log::error!("Step {}: failed to drain: {}", h, e);
+                            terminate_step(h, StepTerminationStatus::Failed);
+                        },
+                    }
+                });
+            });
+            Ok(())
+        }))
+    }
+}
+
+fn control_result(r: Result<Result<(), Error>, Error>) -> StepControlFnResult {
+    match r.and_then(|r| r) {
+        Ok(_) => StepControlFnResult::Ok,
+        Err(e) => StepControlFnResult::ErrorMisc(e.into()),
+    }
+}
+
+/// True if an `export_fn__*` feature exports a function which `export_pipeline_module!` defines as well.
+/// Such a library cannot be linked, so the macro fails the build instead
+#[doc(hidden)]
+pub const EXPORTS_CLASH_WITH_MACRO: bool = cfg!(any(
+    feature="export_fn__free_char_ptr",
+    feature="export_fn__free_record",
+    feature="export_fn__lib_get_metrics",
+    feature="export_fn__lib_get_param_schema",
+    feature="export_fn__lib_pipeline_init",
+    feature="export_fn__pipeline_process_record",
+    feature="export_fn__pipeline_process_records",
+    feature="export_fn__record_ack",
+    feature="export_fn__step_drain",
+    feature="export_fn__step_get_poison_status",
+    feature="export_fn__step_pause",
+    feature="export_fn__step_resume",
+    feature="export_fn__step_set_log_level",
+    feature="export_fn__step_set_param",
+    feature="export_fn__step_shutdown",
+));
+
+/// The `export_fn__*` and `pipeline_module_async_process` features of this crate must be disabled, as they export the same functions
+/// The `export_fn__*` features of this crate must be disabled, as they export the same functions
+#[cfg_attr(any(
+    feature="export_fn__free_char_ptr",
+    feature="export_fn__free_record",
+    feature="export_fn__lib_get_metrics",
+    feature="export_fn__lib_get_param_schema",
+    feature="export_fn__lib_pipeline_init",
+    feature="export_fn__pipeline_process_record",
+    feature="export_fn__pipeline_process_records",
+    feature="export_fn__record_ack",
+    feature="export_fn__step_drain",
+    feature="export_fn__step_get_poison_status",
+    feature="export_fn__step_pause",
+    feature="export_fn__step_resume",
+    feature="export_fn__step_set_log_level",
+    feature="export_fn__step_set_param",
+    feature="export_fn__step_shutdown",
+), doc = "```ignore")]
+#[cfg_attr(not(any(
+    feature="export_fn__free_char_ptr",
+    feature="export_fn__free_record",
+    feature="export_fn__lib_get_metrics",
+    feature="export_fn__lib_get_param_schema",
+    feature="export_fn__lib_pipeline_init",
+    feature="export_fn__pipeline_process_record",
+    feature="export_fn__pipeline_process_records",
+    feature="export_fn__record_ack",
+    feature="export_fn__step_drain",
+    feature="export_fn__step_get_poison_status",
+    feature="export_fn__step_pause",
+    feature="export_fn__step_resume",
+    feature="export_fn__step_set_log_level",
+    feature="export_fn__step_set_param",
+    feature="export_fn__step_shutdown",
+)), doc = "```")]
+/// use torustiq_common::error::Error;
+/// use torustiq_common::ffi::types::module::{ModuleHandle, PipelineModuleKind};
+/// use torustiq_common::pipeline::module::{ConfigureError, PipelineModule};
+/// use torustiq_common::record::OwnedRecord;
+///
+/// struct Discard;
+///
+/// impl PipelineModule for Discard {
+///     const ID: &'static str = "discard";
+///     const NAME: &'static str = "Discard";
+///
+///     fn new(_handle: ModuleHandle) -> Self { Discard }
+///
+///     fn configure(&mut self, kind: PipelineModuleKind) -> Result<(), ConfigureError> {
+///         match kind {
+///             PipelineModuleKind::Destination => Ok(()),
+///             _ => Err(ConfigureError::KindNotSupported),
+///         }
+///     }
+///
+///     fn start(&mut self) -> Result<(), Error> { Ok(()) }
+///
+///     fn process(&mut self, _record: OwnedRecord) -> Result<(), Error> {
+///         Ok(())
+///     }
+/// }
+///
+/// torustiq_common::export_pipeline_module!(Discard);
+///
+/// use torustiq_common::ffi::types::module::{ModulePipelineConfigureArgs, ModulePipelineConfigureFnResult};
+/// let args = ModulePipelineConfigureArgs { kind: PipelineModuleKind::Source, module_handle: 1 };
+/// assert!(matches!(torustiq_module_pipeline_configure(args), ModulePipelineConfigureFnResult::ErrorKindNotSupported));
+/// let args = ModulePipelineConfigureArgs { kind: PipelineModuleKind::Destination, module_handle: 1 };
+/// assert!(matches!(torustiq_module_pipeline_configure(args), ModulePipelineConfigureFnResult::Ok));
+/// ```
+#[macro_export]
+macro_rules! export_pipeline_module {
+    ($module:ty) => {
+        static __TORUSTIQ_MODULE_STEPS: $crate::pipeline::module::ModuleSteps<$module> =
+            $crate::pipeline::module::ModuleSteps::new();
+
+        const _: () = assert!(!$crate::pipeline::module::EXPORTS_CLASH_WITH_MACRO,
+            "export_pipeline_module! exports all C ABI functions of module. Disable the 'export_fn__*' and 'pipeline_module_async_process' features of torustiq-common, \
+            use 'pipeline_queue' for the step queue");
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_lib_get_info() -> $crate::ffi::types::module::LibInfo {
+            $crate::pipeline::module::lib_info_safe::<$module>()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_lib_get_param_schema() -> $crate::ffi::types::params::LibParamSchema {
+            $crate::pipeline::module::param_schema_safe::<$module>()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_lib_get_metrics()
+            -> $crate::ffi::types::collections::Array<$crate::ffi::types::metrics::MetricSample> {
+            $crate::pipeline::module::metrics_safe()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_lib_pipeline_init(a: $crate::ffi::types::module::LibPipelineInitArgs)
+            -> $crate::ffi::types::module::LibInitFnResult {
+            match $crate::ffi::utils::panic::catch_panic("torustiq_lib_pipeline_init", None,
+                || $crate::pipeline::module::init_lib::<$module>(a)) {
+                Ok(r) => r,
+                Err(e) => $crate::ffi::types::module::LibInitFnResult::ErrorMisc(e.into()),
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_pipeline_configure(a: $crate::ffi::types::module::ModulePipelineConfigureArgs)
+            -> $crate::ffi::types::module::ModulePipelineConfigureFnResult {
+            __TORUSTIQ_MODULE_STEPS.configure(a)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_start(h: $crate::ffi::types::module::ModuleHandle)
+            -> $crate::ffi::types::module::StepStartFnResult {
+            __TORUSTIQ_MODULE_STEPS.start(h)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_pipeline_process_record(h: $crate::ffi::types::module::ModuleHandle,
+            r: $crate::ffi::types::module::Record) -> $crate::ffi::types::module::ModulePipelineProcessRecordFnResult {
+            __TORUSTIQ_MODULE_STEPS.process(h, r)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_pipeline_process_records(h: $crate::ffi::types::module::ModuleHandle,
+            r: $crate::ffi::types::collections::Array<$crate::ffi::types::module::Record>)
+            -> $crate::ffi::types::module::ModulePipelineProcessRecordsFnResult {
+            __TORUSTIQ_MODULE_STEPS.process_batch(h, r)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_pipeline_record_ack(h: $crate::ffi::types::module::ModuleHandle,
+            id: $crate::ffi::types::module::RecordId, status: $crate::ffi::types::module::RecordAckStatus) {
+            __TORUSTIQ_MODULE_STEPS.ack(h, id, status)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_set_param(h: $crate::ffi::types::module::ModuleHandle,
+            k: $crate::ffi::types::std_types::ConstCharPtr, v: $crate::ffi::types::std_types::ConstCharPtr)
+            -> $crate::ffi::types::module::StepSetParamFnResult {
+            let result = $crate::ffi::utils::panic::catch_panic("torustiq_module_common_set_param", Some(h),
+                || $crate::ffi::shared::set_param(h,
+                    $crate::ffi::utils::strings::cchar_to_string(k),
+                    $crate::ffi::utils::strings::cchar_to_string(v)));
+            match result.and_then(|r| r) {
+                Ok(_) => $crate::ffi::types::module::StepSetParamFnResult::Ok,
+                Err(e) => $crate::ffi::types::module::StepSetParamFnResult::ErrorMisc(e.into()),
+            }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_set_log_level(h: $crate::ffi::types::module::ModuleHandle,
+            level: $crate::ffi::types::logging::LogLevel) {
+            let _ = $crate::ffi::utils::panic::catch_panic("torustiq_module_common_set_log_level", Some(h),
+                || $crate::logging::set_step_log_level(h, level.into()));
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_shutdown(h: $crate::ffi::types::module::ModuleHandle) {
+            __TORUSTIQ_MODULE_STEPS.shutdown(h)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_pause(h: $crate::ffi::types::module::ModuleHandle)
+            -> $crate::ffi::types::module::StepControlFnResult {
+            __TORUSTIQ_MODULE_STEPS.pause(h)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_resume(h: $crate::ffi::types::module::ModuleHandle)
+            -> $crate::ffi::types::module::StepControlFnResult {
+            __TORUSTIQ_MODULE_STEPS.resume(h)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_drain(h: $crate::ffi::types::module::ModuleHandle,
+            timeout_ms: $crate::ffi::types::std_types::Uint) -> $crate::ffi::types::module::StepControlFnResult {
+            __TORUSTIQ_MODULE_STEPS.drain(h, timeout_ms)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_pipeline_free_record(r: $crate::ffi::types::module::Record) {
+            let _ = $crate::ffi::utils::panic::catch_panic("torustiq_module_pipeline_free_record", None,
+                || $crate::ffi::shared::do_free_record(r));
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_free_char(c: $crate::ffi::types::std_types::ConstCharPtr) {
+            let _ = $crate::ffi::utils::panic::catch_panic("torustiq_module_common_free_char", None,
+                || $crate::ffi::utils::strings::cchar_const_deallocate(c));
+        }
+
+        #[no_mangle]
+        pub extern "C" fn torustiq_module_common_get_poison_status(h: $crate::ffi::types::module::ModuleHandle)
+            -> $crate::ffi::types::module::StepPoisonStatus {
+            $crate::ffi::shared::get_step_poison_status_safe(h)
+        }
+    };
+}
@@ -0,0 +1,330 @@
+//! A runner for destination steps. The runner takes records from the step queue, buffers them
+//! and passes the buffer to `Sink` once a flush threshold is reached. Failed writes are retried
+//! with exponential backoff; records are freed after they're written.
+//! Records which cannot be written are passed to dead letter queue
+
+use std::{
+    sync::{mpsc::RecvTimeoutError, Arc},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
+
+use crate::{
+    dead_letter::dead_letter,
+    error::{Error, ERROR_CODE_INVALID_PARAM, ERROR_CODE_STEP_NOT_READY},
+    ffi::{
+        types::module::{ModuleHandle, StepTerminationStatus},
+        utils::panic::catch_panic,
+    },
+    lifecycle::{complete_drain, terminate_step},
+    logging::enter_step,
+    metrics::{counter, Counter},
+    params::{get_duration, get_param_as, ParamSpec, ParamType},
+    pipeline::{
+        async_process::{close_queue, get_receiver_owned, RecordReceiver},
+        backoff::{backoff_params, Backoff, BackoffConfig, StopSignal},
+    },
+    record::OwnedRecord,
+};
+
+/// A step param with the number of buffered records which triggers a flush
+pub const PARAM_FLUSH_MAX_RECORDS: &str = "flush_max_records";
+/// A step param with the total content size of buffered records which triggers a flush
+pub const PARAM_FLUSH_MAX_BYTES: &str = "flush_max_bytes";
+/// A step param with the maximum time a record stays in buffer
+pub const PARAM_FLUSH_INTERVAL: &str = "flush_interval";
+
+/// Retries of a failed write after the runner is stopped, so stopping doesn't take long
+const STOPPED_RETRIES: u32 = 3;
+/// Maximum delay between retries after the runner is stopped
+const STOPPED_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A destination of records, e.g. a database or a file
+pub trait Sink: Send + 'static {
+    /// Writes a batch of buffered records. If a retryable error is returned, the whole batch is written again,
+    /// so the sink should tolerate duplicates of partially written batches
+    fn write(&mut self, records: &[OwnedRecord]) -> Result<(), Error>;
+
+    /// Called after the final flush when the runner stops
+    fn close(&mut self) {}
+}
+
+/// Thresholds for flushing the buffer. The buffer is flushed when any of them is reached
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushPolicy {
+    pub max_records: usize,
+    /// No limit if not set
+    pub max_bytes: Option<usize>,
+    pub interval: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            max_records: 100,
+            max_bytes: None,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl FlushPolicy {
+    /// Reads the policy from step params. Params which are not set keep default values.
+    /// Size thresholds must be greater than zero
+    /// ```
+    /// use torustiq_common::ffi::shared::set_param;
+    /// use torustiq_common::pipeline::sink::FlushPolicy;
+    /// set_param(12, "flush_max_bytes", "0").unwrap();
+    /// assert_eq!(FlushPolicy::from_params(12).unwrap_err().param_name, Some("flush_max_bytes".to_string()));
+    /// ```
+    pub fn from_params(h: ModuleHandle) -> Result<Self, Error> {
+        let default = FlushPolicy::default();
+        Ok(FlushPolicy {
+            max_records: get_positive_param(h, PARAM_FLUSH_MAX_RECORDS)?.unwrap_or(default.max_records),
+            max_bytes: get_positive_param(h, PARAM_FLUSH_MAX_BYTES)?.or(default.max_bytes),
+            interval: get_duration(h, PARAM_FLUSH_INTERVAL)?.unwrap_or(default.interval),
+        })
+    }
+
+    fn is_due(&self, buffer: &Buffer) -> bool {
+        if buffer.records.is_empty() {
+            return false;
+        }
+        buffer.records.len() >= self.max_records
+            || self.max_bytes.is_some_and(|max| buffer.bytes >= max)
+            || buffer.since.is_some_and(|t| t.elapsed() >= self.interval)
+    }
+}
+
+fn get_positive_param(h: ModuleHandle, name: &str) -> Result<Option<usize>, Error> {
+    match get_param_as::<usize>(h, name)? {
+        Some(0) => Err(Error::config(format!("The '{}' param must be greater than zero", name))
+            .with_param(name)
+            .with_code(ERROR_CODE_INVALID_PARAM)),
+        value => Ok(value),
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SinkConfig {
+    pub flush: FlushPolicy,
+    pub backoff: BackoffConfig,
+}
+
+impl SinkConfig {
+    /// Reads the config from step params. Params which are not set keep default values
+    pub fn from_params(h: ModuleHandle) -> Result<Self, Error> {
+        Ok(SinkConfig {
+            flush: FlushPolicy::from_params(h)?,
+            backoff: BackoffConfig::from_params(h)?,
+        })
+    }
+}
+
+/// Params read by `SinkConfig::from_params`
+pub fn sink_params() -> Vec<ParamSpec> {
+    let mut params = vec![
+        ParamSpec::new(PARAM_FLUSH_MAX_RECORDS, ParamType::Int)
+            .default_value("100")
+            .description("Number of buffered records which triggers a flush"),
+        ParamSpec::new(PARAM_FLUSH_MAX_BYTES, ParamType::Int)
+            .description("Total content size of buffered records in bytes which triggers a flush"),
+        ParamSpec::new(PARAM_FLUSH_INTERVAL, ParamType::Duration)
+            .default_value("1s")
+            .description("Maximum time a record stays in buffer before flush"),
+    ];
+    params.extend(backoff_params());
+    params
+}
+
+/// Records waiting for flush
+#[derive(Default)]
+struct Buffer {
+    records: Vec<OwnedRecord>,
+    bytes: usize,
+    /// When the oldest record was added
+    since: Option<Instant>,
+}
+
+impl Buffer {
+    fn push(&mut self, r: OwnedRecord) {
+        self.bytes += r.content().len();
+        self.since.get_or_insert_with(Instant::now);
+        self.records.push(r);
+    }
+
+    /// Drops the records, so they're freed
+    fn clear(&mut self) {
+        self.records.clear();
+        self.bytes = 0;
+        self.since = None;
+    }
+}
+
+struct SinkMetrics {
+    written: Counter,
+    errors: Counter,
+}
+
+/// A running sink. The runner is stopped when dropped
+pub struct SinkRunner {
+    h: ModuleHandle,
+    stop: Arc<StopSignal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SinkRunner {
+    /// Closes the step queue, flushes the remaining records and waits until the sink is closed.
+    /// Once stopped, a failed write is retried at most 3 more times with short delays, so stopping doesn't
+    /// block for long. If the write still fails, the buffered records are passed to dead letter queue
+    pub fn stop(self) {}
+
+    /// Waits until the runner stops by itself, e.g. when the queue is closed by drain or the sink failed
+    pub fn join(mut self) {
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for SinkRunner {
+    fn drop(&mut self) {
+        close_queue(self.h);
+        self.stop.stop();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// Takes the queue receiver of step and starts writing its records to sink in a dedicated thread.
+/// When the queue is closed, the buffer is flushed and the drain of step is completed.
+/// If a write fails and cannot be retried, the step is terminated with the 'failed' status
+/// and the buffered records are passed to dead letter queue
+/// ```
+/// use std::{collections::HashMap, sync::{Arc, Mutex}};
+/// use torustiq_common::error::Error;
+/// use torustiq_common::ffi::{shared::set_param, types::module::Record};
+/// use torustiq_common::pipeline::{async_process::*, sink::*};
+/// use torustiq_common::record::OwnedRecord;
+///
+/// struct Collector(Arc<Mutex<Vec<Vec<u8>>>>);
+///
+/// impl Sink for Collector {
+///     fn write(&mut self, records: &[OwnedRecord]) -> Result<(), Error> {
+///         self.0.lock().unwrap().push(records.iter().map(|r| r.content()[0]).collect());
+///         Ok(())
+///     }
+/// }
+///
+/// set_param(10, "flush_max_records", "2").unwrap();
+/// create_sender_and_receiver(10).unwrap();
+/// let batches = Arc::new(Mutex::new(Vec::new()));
+/// let runner = start_sink(10, Collector(batches.clone()), SinkConfig::from_params(10).unwrap()).unwrap();
+/// for i in 0..3 {
+///     torustiq_module_pipeline_process_record(10, Record::from_std_types(vec![i], HashMap::new()));
+/// }
+/// // The last record is written by the final flush
+/// runner.stop();
+/// assert_eq!(*batches.lock().unwrap(), vec![vec![0, 1], vec![2]]);
+/// ```
+pub fn start_sink<S: Sink>(h: ModuleHandle, sink: S, config: SinkConfig) -> Result<SinkRunner, Error> {
+    let receiver = get_receiver_owned(h).ok_or_else(|| Error::internal(
+        format!("The queue of step {} doesn't exist or its receiver is already taken", h))
+        .with_code(ERROR_CODE_STEP_NOT_READY))?;
+    let stop = Arc::new(StopSignal::default());
+    let runner_stop = stop.clone();
+    let thread = std::thread::Builder::new()
+        .name(format!("torustiq-sink-{}", h))
+        .spawn(move || run_sink(h, sink, receiver, config, &runner_stop))
+        .map_err(|e| Error::internal(format!("Failed to start the sink thread of step {}: {}", h, e)))?;
+    Ok(SinkRunner { h, stop, thread: Some(thread) })
+}
+
+fn run_sink<S: Sink>(h: ModuleHandle, mut sink: S, receiver: RecordReceiver, config: SinkConfig, stop: &StopSignal) {
+    let _scope = enter_step(h);
+    let metrics = SinkMetrics {
+        written: counter(h, "torustiq_sink_records_written_total", "Records written by sink"),
+        errors: counter(h, "torustiq_sink_write_errors_total", "Failed writes of sink, including retried ones"),
+    };
+    let mut backoff = Backoff::new(config.backoff.clone());
+    let mut buffer = Buffer::default();
+    let mut result = Ok(());
+    while result.is_ok() {
+        let timeout = match buffer.since {
+            Some(t) => config.flush.interval.saturating_sub(t.elapsed()),
+            None => config.flush.interval,
+        };
+        match receiver.recv_timeout_for_forwarding(timeout) {
+            // Records in queue are passed by host for this step only
+            Ok(r) => buffer.push(unsafe { OwnedRecord::from_raw(r) }),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if config.flush.is_due(&buffer) {
+            result = flush(h, &mut sink, &mut buffer, &mut backoff, &metrics, stop);
+        }
+    }
+    // The final flush
+    if result.is_ok() && !buffer.records.is_empty() {
+        result = flush(h, &mut sink, &mut buffer, &mut backoff, &metrics, stop);
+    }
+    catch_panic("sink close", Some(h), || sink.close()).ok();
+    match result {
+        Ok(()) => {
+            complete_drain(h);
+        },
+        Err(e) => {
+            error!("Step {}: sink failed, {} buffered records are passed to dead letter queue: {}",
+                h, buffer.records.len(), e);
+            let attempts = backoff.attempts() + 1;
+            for r in buffer.records.drain(..) {
+                dead_letter(h, r, &e, attempts);
+            }
+            terminate_step(h, StepTerminationStatus::Failed);
+        },
+    }
+}
+
+/// Writes the buffer and clears it on success. Retryable errors are retried until backoff gives up.
+/// After the runner is stopped, the number of retries and their delays are limited
+fn flush<S: Sink>(h: ModuleHandle, sink: &mut S, buffer: &mut Buffer, backoff: &mut Backoff,
+    metrics: &SinkMetrics, stop: &StopSignal) -> Result<(), Error>
+{
+    let mut stopped_retries = 0;
+    loop {
+        let e = match catch_panic("sink write", Some(h), || sink.write(&buffer.records)).and_then(|r| r) {
+            Ok(()) => {
+                metrics.written.add(buffer.records.len() as u64);
+                buffer.clear();
+                backoff.reset();
+                return Ok(());
+            },
+            Err(e) => e,
+        };
+        metrics.errors.inc();
+        if !e.retryable {
+            return Err(e);
+        }
+        let mut delay = match backoff.next_delay() {
+            Some(d) => d,
+            None => return Err(e),
+        };
+        let stopped = stop.is_stopped();
+        if stopped {
+            if stopped_retries >= STOPPED_RETRIES {
+                return Err(e);
+            }
+            stopped_retries += 1;
+            delay = delay.min(STOPPED_RETRY_DELAY);
+        }
+        warn!("Step {}: write failed (attempt {}), retrying in {:?}: {}", h, backoff.attempts(), delay, e);
+        match stopped {
+            true => std::thread::sleep(delay),
+            // If the runner is stopped while waiting, the write is retried at once
+            false => { stop.wait(delay); },
+        }
+    }
+}
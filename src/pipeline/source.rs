@@ -0,0 +1,251 @@
+//! A runner for source steps. Implement `Source` and export `SourceModule<YourSource>` with
+//! `export_pipeline_module!`: the runner polls the source in a dedicated thread, passes records to host,
+//! waits between polls if no data is available and retries failed polls with exponential backoff
+
+use std::{
+    marker::PhantomData,
+    sync::Arc,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{error, warn};
+
+use crate::{
+    error::Error,
+    ffi::{
+        shared::emit_records,
+        types::module::{ModuleHandle, PipelineModuleKind, StepTerminationStatus},
+        utils::panic::catch_panic,
+    },
+    lifecycle::{get_step_state, terminate_step, wait_while_paused, StepState},
+    logging::enter_step,
+    metrics::counter,
+    params::{get_duration, ParamSchema, ParamSpec, ParamType},
+    pipeline::{
+        backoff::{backoff_params, Backoff, BackoffConfig, StopSignal},
+        module::{ConfigureError, PipelineModule},
+    },
+    record::OwnedRecord,
+};
+
+/// A step param with the delay between polls when the source has no data
+pub const PARAM_POLL_INTERVAL: &str = "poll_interval";
+
+/// A source of records, e.g. a message broker consumer or a generator
+pub trait Source: Send + 'static {
+    /// Module identifier, e.g. 'kafka_source'
+    const ID: &'static str;
+    /// Human-readable module name
+    const NAME: &'static str;
+
+    /// Returns the params accepted by source. Params of runner are added automatically
+    fn param_schema() -> Option<ParamSchema> where Self: Sized {
+        None
+    }
+
+    /// Creates the source when step starts. Step params are available at this point
+    fn open(handle: ModuleHandle) -> Result<Self, Error> where Self: Sized;
+
+    /// Returns new records. An empty vector means there is no data at the moment.
+    /// Retryable errors are retried with backoff; other errors terminate the step
+    fn poll(&mut self) -> Result<Vec<OwnedRecord>, Error>;
+
+    /// Called when the runner stops
+    fn close(&mut self) {}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceConfig {
+    /// Delay between polls when the source has no data
+    pub poll_interval: Duration,
+    pub backoff: BackoffConfig,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig {
+            poll_interval: Duration::from_secs(1),
+            backoff: BackoffConfig::default(),
+        }
+    }
+}
+
+impl SourceConfig {
+    /// Reads the config from step params. Params which are not set keep default values
+    pub fn from_params(h: ModuleHandle) -> Result<Self, Error> {
+        Ok(SourceConfig {
+            poll_interval: get_duration(h, PARAM_POLL_INTERVAL)?.unwrap_or(SourceConfig::default().poll_interval),
+            backoff: BackoffConfig::from_params(h)?,
+        })
+    }
+}
+
+/// Params read by `SourceConfig::from_params`
+pub fn source_params() -> Vec<ParamSpec> {
+    let mut params = vec![
+        ParamSpec::new(PARAM_POLL_INTERVAL, ParamType::Duration)
+            .default_value("1s")
+            .description("Delay between polls when the source has no data"),
+    ];
+    params.extend(backoff_params());
+    params
+}
+
+/// A running source. The source is stopped when the runner is dropped
+pub struct SourceRunner {
+    stop: Arc<StopSignal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SourceRunner {
+    /// Stops polling and waits until the source is closed
+    pub fn stop(self) {}
+
+    /// Waits until the runner stops by itself, e.g. because of a failure
+    pub fn join(mut self) {
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for SourceRunner {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+/// Starts polling the source in a dedicated thread.
+/// The runner stops polling if step is draining or terminated, and waits while step is paused
+/// ```
+/// use torustiq_common::error::Error;
+/// use torustiq_common::ffi::types::module::{ModuleHandle, StepTerminationStatus};
+/// use torustiq_common::lifecycle::{get_step_state, StepState};
+/// use torustiq_common::pipeline::source::{start_source, Source, SourceConfig};
+/// use torustiq_common::record::OwnedRecord;
+///
+/// struct Broken;
+///
+/// impl Source for Broken {
+///     const ID: &'static str = "broken";
+///     const NAME: &'static str = "Broken";
+///     fn open(_: ModuleHandle) -> Result<Self, Error> { Ok(Broken) }
+///     fn poll(&mut self) -> Result<Vec<OwnedRecord>, Error> { Err(Error::data("corrupted input")) }
+/// }
+///
+/// start_source(9, Broken, SourceConfig::default()).unwrap().join();
+/// assert_eq!(get_step_state(9), StepState::Terminated(StepTerminationStatus::Failed));
+/// ```
+pub fn start_source<S: Source>(h: ModuleHandle, source: S, config: SourceConfig) -> Result<SourceRunner, Error> {
+    let stop = Arc::new(StopSignal::default());
+    let runner_stop = stop.clone();
+    let thread = std::thread::Builder::new()
+        .name(format!("torustiq-source-{}", h))
+        .spawn(move || run_source(h, source, config, &runner_stop))
+        .map_err(|e| Error::internal(format!("Failed to start the source thread of step {}: {}", h, e)))?;
+    Ok(SourceRunner { stop, thread: Some(thread) })
+}
+
+fn run_source<S: Source>(h: ModuleHandle, mut source: S, config: SourceConfig, stop: &StopSignal) {
+    let _scope = enter_step(h);
+    let records_out = counter(h, "torustiq_records_out_total", "Records emitted by step");
+    let mut backoff = Backoff::new(config.backoff.clone());
+    while !stop.is_stopped() {
+        match get_step_state(h) {
+            StepState::Running => {},
+            StepState::Paused => {
+                wait_while_paused(h, config.poll_interval);
+                continue;
+            },
+            StepState::Draining | StepState::Terminated(_) => break,
+        }
+        match catch_panic("source poll", Some(h), || source.poll()).and_then(|r| r) {
+            Ok(records) if records.is_empty() => {
+                backoff.reset();
+                stop.wait(config.poll_interval);
+            },
+            Ok(records) => {
+                backoff.reset();
+                let count = records.len() as u64;
+                if !emit_records(h, records) {
+                    error!("Step {}: cannot pass records to host, the library is not initialized", h);
+                    terminate_step(h, StepTerminationStatus::Failed);
+                    break;
+                }
+                records_out.add(count);
+            },
+            Err(e) => match (e.retryable, backoff.next_delay()) {
+                (true, Some(delay)) => {
+                    warn!("Step {}: poll failed (attempt {}), retrying in {:?}: {}", h, backoff.attempts(), delay, e);
+                    stop.wait(delay);
+                },
+                _ => {
+                    error!("Step {}: source failed: {}", h, e);
+                    terminate_step(h, StepTerminationStatus::Failed);
+                    break;
+                },
+            },
+        }
+    }
+    catch_panic("source close", Some(h), || source.close()).ok();
+}
+
+/// A pipeline module which runs a source. Use it with `export_pipeline_module!(SourceModule<YourSource>)`
+pub struct SourceModule<S: Source> {
+    h: ModuleHandle,
+    runner: Option<SourceRunner>,
+    _source: PhantomData<fn() -> S>,
+}
+
+impl<S: Source> PipelineModule for SourceModule<S> {
+    const ID: &'static str = S::ID;
+    const NAME: &'static str = S::NAME;
+
+    /// Params of runner are added to the schema of source. If the source doesn't provide a schema,
+    /// unknown params are accepted
+    fn param_schema() -> Option<ParamSchema> {
+        let mut schema = S::param_schema().unwrap_or_else(|| ParamSchema::new().allow_unknown(true));
+        for spec in source_params() {
+            if schema.get(&spec.name).is_none() {
+                schema.params.push(spec);
+            }
+        }
+        Some(schema)
+    }
+
+    fn new(handle: ModuleHandle) -> Self {
+        SourceModule { h: handle, runner: None, _source: PhantomData }
+    }
+
+    fn configure(&mut self, kind: PipelineModuleKind) -> Result<(), ConfigureError> {
+        match kind {
+            PipelineModuleKind::Source => Ok(()),
+            _ => Err(ConfigureError::KindNotSupported),
+        }
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        let config = SourceConfig::from_params(self.h)?;
+        let source = S::open(self.h)?;
+        self.runner = Some(start_source(self.h, source, config)?);
+        Ok(())
+    }
+
+    fn process(&mut self, _record: OwnedRecord) -> Result<(), Error> {
+        Err(Error::internal(format!("Step {} is a source and doesn't accept records", self.h)))
+    }
+
+    /// Sources don't buffer records, so the runner is just stopped
+    fn drain(&mut self) -> Result<(), Error> {
+        self.runner.take();
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        self.runner.take();
+    }
+}
@@ -0,0 +1,221 @@
+//! Tokio integration for step queues. Records of step are received as an async `Stream`,
+//! and tasks of steps run on a runtime shared by all steps of library.
+//! Tasks of step are aborted when the step is shut down; the runtime stops with the last step using it,
+//! unless its handle is taken by `runtime_handle`
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use once_cell::sync::Lazy;
+use tokio::{
+    runtime::{Builder, Handle, Runtime},
+    sync::mpsc,
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    error::Error,
+    ffi::{
+        shared::{emit_record, emit_records},
+        types::module::{ModuleHandle, Record},
+        utils::panic::lock_or_recover,
+    },
+    lifecycle::complete_drain,
+    logging::enter_step,
+    pipeline::async_process::{close_queue, get_receiver_owned},
+    record::OwnedRecord,
+};
+
+static RUNTIME: Lazy<Mutex<SharedRuntime>> = Lazy::new(|| {
+    Mutex::new(SharedRuntime::default())
+});
+
+/// Steps which use the runtime and their spawned tasks
+static STEP_TASKS: Lazy<Mutex<HashMap<ModuleHandle, Vec<AbortHandle>>>> = Lazy::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+#[derive(Default)]
+struct SharedRuntime {
+    runtime: Option<Runtime>,
+    /// The handle is used outside of steps, so the runtime is not stopped with the last step
+    pinned: bool,
+}
+
+/// Returns a handle of the runtime shared by all steps of library. The runtime is started on first call.
+/// As the library cannot know when the handle is not used anymore, the runtime is not stopped
+/// after this call, even when all steps are shut down
+pub fn runtime_handle() -> Result<Handle, Error> {
+    let mut shared = lock_or_recover(&RUNTIME);
+    shared.pinned = true;
+    start_runtime(&mut shared)
+}
+
+/// Returns a handle of the shared runtime for a step. The runtime might stop with the last step
+fn step_runtime_handle() -> Result<Handle, Error> {
+    start_runtime(&mut lock_or_recover(&RUNTIME))
+}
+
+fn start_runtime(shared: &mut SharedRuntime) -> Result<Handle, Error> {
+    if let Some(rt) = shared.runtime.as_ref() {
+        return Ok(rt.handle().clone());
+    }
+    let rt = Builder::new_multi_thread()
+        .thread_name("torustiq-tokio")
+        .enable_all()
+        .build()
+        .map_err(|e| Error::internal(format!("Failed to start the tokio runtime: {}", e)))?;
+    let handle = rt.handle().clone();
+    shared.runtime = Some(rt);
+    Ok(handle)
+}
+
+/// Runs a task of step on the shared runtime. Log records of task are attributed to step.
+/// The task is aborted when the step is shut down
+pub fn spawn_step_task<F>(h: ModuleHandle, task: F) -> Result<JoinHandle<F::Output>, Error>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let join = step_runtime_handle()?.spawn(InStep { h, inner: Box::pin(task) });
+    let mut tasks = lock_or_recover(&STEP_TASKS);
+    let step_tasks = tasks.entry(h).or_default();
+    step_tasks.retain(|t| !t.is_finished());
+    step_tasks.push(join.abort_handle());
+    Ok(join)
+}
+
+/// Aborts tasks of step and closes its queue. Stops the runtime if no other step uses it
+/// and its handle was not taken by `runtime_handle`
+pub fn shutdown_step(h: ModuleHandle) {
+    close_queue(h);
+    let (tasks, is_last) = {
+        let mut all_tasks = lock_or_recover(&STEP_TASKS);
+        let tasks = all_tasks.remove(&h);
+        (tasks, all_tasks.is_empty())
+    };
+    let tasks = match tasks {
+        Some(t) => t,
+        // The step doesn't use the runtime
+        None => return,
+    };
+    tasks.iter().for_each(|t| t.abort());
+    if is_last {
+        // Doesn't wait for tasks, as this function might be called from a task
+        let mut shared = lock_or_recover(&RUNTIME);
+        if !shared.pinned {
+            if let Some(rt) = shared.runtime.take() {
+                rt.shutdown_background();
+            }
+        }
+    }
+}
+
+/// Makes the step current for thread while the inner future is polled
+struct InStep<F: Future> {
+    h: ModuleHandle,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for InStep<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _scope = enter_step(self.h);
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+/// Records of step queue as an async stream. The stream ends when the queue is closed and empty;
+/// if the step is draining, the drain is completed at this point.
+/// Records which are forwarded to the stream but not received are freed when the stream is dropped
+pub struct RecordStream {
+    h: ModuleHandle,
+    receiver: mpsc::Receiver<Record>,
+}
+
+impl RecordStream {
+    /// Converts the stream into a stream of owned records, so they're freed automatically
+    pub fn owned(self) -> impl Stream<Item = OwnedRecord> {
+        // Records in queue are passed by host for this step only
+        self.map(|r| unsafe { OwnedRecord::from_raw(r) })
+    }
+}
+
+impl Stream for RecordStream {
+    type Item = Record;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let result = self.receiver.poll_recv(cx);
+        if let Poll::Ready(None) = result {
+            complete_drain(self.h);
+        }
+        result
+    }
+}
+
+impl Drop for RecordStream {
+    fn drop(&mut self) {
+        // Makes the forwarding task stop, so no records are sent after draining
+        self.receiver.close();
+        while let Ok(mut r) = self.receiver.try_recv() {
+            r.free_contents();
+        }
+    }
+}
+
+/// Takes the queue receiver of step and returns its records as a stream.
+/// Records are moved from the queue by a blocking task of the shared runtime.
+/// Returns None if the receiver is already taken
+/// ```
+/// use std::collections::HashMap;
+/// use tokio_stream::StreamExt;
+/// use torustiq_common::ffi::types::module::Record;
+/// use torustiq_common::pipeline::{async_process::*, tokio_runtime::*};
+///
+/// create_sender_and_receiver(3).unwrap();
+/// let stream = get_record_stream(3).unwrap().unwrap();
+/// torustiq_module_pipeline_process_record(3, Record::from_std_types(vec![1], HashMap::new()));
+/// close_queue(3);
+/// let records: Vec<Vec<u8>> = runtime_handle().unwrap().block_on(async move {
+///     stream.owned().map(|r| r.content().to_vec()).collect().await
+/// });
+/// assert_eq!(records, vec![vec![1]]);
+/// shutdown_step(3);
+/// ```
+pub fn get_record_stream(h: ModuleHandle) -> Result<Option<RecordStream>, Error> {
+    let runtime = step_runtime_handle()?;
+    let receiver = match get_receiver_owned(h) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    lock_or_recover(&STEP_TASKS).entry(h).or_default();
+    // A single slot, so records wait in the step queue and the queue capacity still applies
+    let (tx, rx) = mpsc::channel::<Record>(1);
+    runtime.spawn_blocking(move || {
+        while let Ok(r) = receiver.recv_for_forwarding() {
+            if let Err(mut e) = tx.blocking_send(r) {
+                // The stream is dropped
+                e.0.free_contents();
+                break;
+            }
+        }
+    });
+    Ok(Some(RecordStream { h, receiver: rx }))
+}
+
+/// Passes a record to host without blocking the async runtime
+pub async fn emit_record_async(h: ModuleHandle, r: OwnedRecord) -> bool {
+    tokio::task::spawn_blocking(move || emit_record(h, r)).await.unwrap_or(false)
+}
+
+/// Passes several records to host without blocking the async runtime
+pub async fn emit_records_async(h: ModuleHandle, records: Vec<OwnedRecord>) -> bool {
+    tokio::task::spawn_blocking(move || emit_records(h, records)).await.unwrap_or(false)
+}
@@ -0,0 +1,259 @@
+//! Parallel processing of step queue. Several workers consume records from the queue of a single step.
+//! If the 'ordering_key' param is set, records with the same value of this metadata key are processed
+//! by the same worker in the order of arrival
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use log::error;
+
+use crate::{
+    dead_letter::dead_letter,
+    error::{Error, ERROR_CODE_INVALID_PARAM, ERROR_CODE_STEP_NOT_READY},
+    ffi::{
+        types::module::{ModuleHandle, Record},
+        utils::panic::{catch_panic, lock_or_recover},
+    },
+    lifecycle::complete_drain,
+    logging::enter_step,
+    metrics::ProcessMetrics,
+    params::get_param_as,
+    pipeline::async_process::{get_receiver_owned, RecordReceiver},
+    record::OwnedRecord,
+};
+
+/// A step param with the number of workers
+pub const PARAM_WORKERS: &str = "workers";
+/// A step param with the metadata key used to preserve order of records
+pub const PARAM_ORDERING_KEY: &str = "ordering_key";
+
+/// A result of record handler. On failure the handler returns the record with error,
+/// so the record is passed to dead letter queue without copying it beforehand
+/// ```
+/// use std::{collections::HashMap, sync::{Arc, Mutex}};
+/// use torustiq_common::{dead_letter::*, error::Error, record::OwnedRecord};
+/// use torustiq_common::ffi::types::module::Record;
+/// use torustiq_common::pipeline::{async_process::*, worker_pool::start_worker_pool};
+///
+/// struct Collector(Mutex<Vec<OwnedRecord>>);
+///
+/// impl DeadLetterHandler for Collector {
+///     fn handle(&self, record: OwnedRecord) -> Result<(), Error> {
+///         self.0.lock().unwrap().push(record);
+///         Ok(())
+///     }
+/// }
+///
+/// let collector = Arc::new(Collector(Mutex::new(Vec::new())));
+/// set_dead_letter_handler(14, collector.clone());
+/// create_sender_and_receiver(14).unwrap();
+/// let pool = start_worker_pool(14, |r| Err((Error::data("unexpected format"), r))).unwrap();
+/// torustiq_module_pipeline_process_record(14, Record::from_std_types(vec![7], HashMap::new()));
+/// close_queue(14);
+/// pool.join();
+/// assert_eq!(collector.0.lock().unwrap()[0].content(), &[7]);
+/// ```
+pub type HandlerResult = Result<(), (Error, OwnedRecord)>;
+
+/// Number of records buffered per worker in ordered mode
+const WORKER_QUEUE_CAPACITY: usize = 16;
+
+/// Running workers of step
+pub struct WorkerPool {
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Number of threads, including the dispatcher in ordered mode
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Waits until all workers exit. Workers exit once the step queue is closed and empty
+    pub fn join(self) {
+        for t in self.threads {
+            let _ = t.join();
+        }
+    }
+}
+
+/// Tracks running workers. The drain of step is completed when the last worker exits
+struct WorkerGuard {
+    h: ModuleHandle,
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if self.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            complete_drain(self.h);
+        }
+    }
+}
+
+/// Takes the queue receiver of step and starts workers which pass records to handler.
+/// The number of workers and ordering key are read from step params.
+/// Records which handler returns with error are passed to dead letter queue. A panic poisons the step
+/// ```
+/// use std::{collections::HashMap, sync::{Arc, Mutex}};
+/// use torustiq_common::ffi::{shared::set_param, types::module::Record};
+/// use torustiq_common::pipeline::{async_process::*, worker_pool::start_worker_pool};
+///
+/// set_param(8, "workers", "3").unwrap();
+/// set_param(8, "ordering_key", "user").unwrap();
+/// create_sender_and_receiver(8).unwrap();
+/// let seen = Arc::new(Mutex::new(Vec::new()));
+/// let seen_by_workers = seen.clone();
+/// let pool = start_worker_pool(8, move |r| {
+///     seen_by_workers.lock().unwrap().push(r.content()[0]);
+///     Ok(())
+/// }).unwrap();
+/// for i in 0..10 {
+///     let metadata = HashMap::from([("user".to_string(), "alice".to_string())]);
+///     torustiq_module_pipeline_process_record(8, Record::from_std_types(vec![i], metadata));
+/// }
+/// close_queue(8);
+/// pool.join();
+/// // Records with the same key are processed in order
+/// assert_eq!(*seen.lock().unwrap(), (0..10).collect::<Vec<u8>>());
+/// ```
+pub fn start_worker_pool<F>(h: ModuleHandle, handler: F) -> Result<WorkerPool, Error>
+where
+    F: Fn(OwnedRecord) -> HandlerResult + Send + Sync + 'static,
+{
+    let workers = get_param_as::<usize>(h, PARAM_WORKERS)?.unwrap_or(1);
+    if workers == 0 {
+        return Err(Error::config(format!("The '{}' param must be greater than zero", PARAM_WORKERS))
+            .with_param(PARAM_WORKERS)
+            .with_code(ERROR_CODE_INVALID_PARAM));
+    }
+    let ordering_key = get_param_as::<String>(h, PARAM_ORDERING_KEY)?;
+    let receiver = get_receiver_owned(h).ok_or_else(|| Error::internal(
+        format!("The queue of step {} doesn't exist or its receiver is already taken", h))
+        .with_code(ERROR_CODE_STEP_NOT_READY))?;
+    let handler = Arc::new(handler);
+    let running = Arc::new(AtomicUsize::new(workers));
+    let threads = match ordering_key {
+        Some(key) => start_ordered(h, receiver, workers, key, handler, running)?,
+        None => start_unordered(h, receiver, workers, handler, running)?,
+    };
+    Ok(WorkerPool { threads })
+}
+
+/// Workers take records from the shared queue. The receiver is shared with a mutex:
+/// an idle worker holds the lock while it waits for a record, so other idle workers wait for the lock
+/// rather than for the queue. Workers release the lock before processing, so processing is parallel
+fn start_unordered<F>(h: ModuleHandle, receiver: RecordReceiver, workers: usize, handler: Arc<F>, running: Arc<AtomicUsize>)
+    -> Result<Vec<JoinHandle<()>>, Error>
+where
+    F: Fn(OwnedRecord) -> HandlerResult + Send + Sync + 'static,
+{
+    let receiver = Arc::new(Mutex::new(receiver));
+    (0..workers)
+        .map(|i| {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            let guard = WorkerGuard { h, running: running.clone() };
+            spawn_worker(h, i, move || {
+                let _guard = guard;
+                let metrics = ProcessMetrics::register(h);
+                loop {
+                    // The lock is released before processing, so other workers can take records
+                    let r = lock_or_recover(&receiver).recv_for_forwarding();
+                    match r {
+                        Ok(r) => handle_record(h, &metrics, handler.as_ref(), r),
+                        Err(_) => break,
+                    }
+                }
+            })
+        })
+        .collect()
+}
+
+/// A dispatcher passes records to workers by hash of the ordering key.
+/// Records without the key are distributed evenly
+fn start_ordered<F>(h: ModuleHandle, receiver: RecordReceiver, workers: usize, key: String, handler: Arc<F>,
+    running: Arc<AtomicUsize>) -> Result<Vec<JoinHandle<()>>, Error>
+where
+    F: Fn(OwnedRecord) -> HandlerResult + Send + Sync + 'static,
+{
+    let mut senders: Vec<SyncSender<Record>> = Vec::with_capacity(workers);
+    let mut threads = Vec::with_capacity(workers + 1);
+    for i in 0..workers {
+        let (tx, rx): (SyncSender<Record>, Receiver<Record>) = sync_channel(WORKER_QUEUE_CAPACITY);
+        senders.push(tx);
+        let handler = handler.clone();
+        let guard = WorkerGuard { h, running: running.clone() };
+        threads.push(spawn_worker(h, i, move || {
+            let _guard = guard;
+            let metrics = ProcessMetrics::register(h);
+            for r in rx.iter() {
+                handle_record(h, &metrics, handler.as_ref(), r);
+            }
+        })?);
+    }
+    let dispatcher = std::thread::Builder::new()
+        .name(format!("torustiq-dispatch-{}", h))
+        .spawn(move || {
+            let _scope = enter_step(h);
+            let mut next = 0;
+            while let Ok(r) = receiver.recv_for_forwarding() {
+                let worker = match r.get_typed_metadata_value(&key) {
+                    Some(v) => key_hash(&v.to_string()) % senders.len(),
+                    None => {
+                        next = (next + 1) % senders.len();
+                        next
+                    },
+                };
+                if let Err(mut e) = senders[worker].send(r) {
+                    // The worker exited because of panic
+                    e.0.free_contents();
+                }
+            }
+            // Senders are dropped here, so workers exit after processing the buffered records
+        })
+        .map_err(|e| Error::internal(format!("Failed to start the dispatcher of step {}: {}", h, e)))?;
+    threads.push(dispatcher);
+    Ok(threads)
+}
+
+fn spawn_worker<F: FnOnce() + Send + 'static>(h: ModuleHandle, i: usize, f: F) -> Result<JoinHandle<()>, Error> {
+    std::thread::Builder::new()
+        .name(format!("torustiq-worker-{}-{}", h, i))
+        .spawn(move || {
+            let _scope = enter_step(h);
+            f()
+        })
+        .map_err(|e| Error::internal(format!("Failed to start worker {} of step {}: {}", i, h, e)))
+}
+
+// The error carries the record, so it's passed to dead letter queue without copying
+#[allow(clippy::result_large_err)]
+fn handle_record<F: Fn(OwnedRecord) -> HandlerResult>(h: ModuleHandle, metrics: &ProcessMetrics, handler: &F, r: Record) {
+    // Records in queue are passed by host for this step only
+    let r = unsafe { OwnedRecord::from_raw(r) };
+    match catch_panic("worker_pool", Some(h), || handler(r)) {
+        Ok(Ok(())) => {},
+        Ok(Err((e, record))) => {
+            metrics.errors.inc();
+            error!("Step {}: failed to process record, passing it to dead letter queue: {}", h, e);
+            dead_letter(h, record, &e, 1);
+        },
+        // The record is freed by unwind
+        Err(_) => metrics.errors.inc(),
+    }
+}
+
+fn key_hash(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
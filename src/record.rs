@@ -0,0 +1,118 @@
+//! A safe Rust wrapper for records
+
+use std::{collections::HashMap, ops::{Deref, DerefMut}};
+
+use crate::ffi::types::{metadata::MetadataValue, module::Record};
+
+/// A record owned by Rust code. Memory is released when the object is dropped.
+/// Use `into_raw` to pass the record over C ABI and `from_raw` to take ownership of received one
+/// ```
+/// use std::collections::HashMap;
+/// use torustiq_common::record::OwnedRecord;
+/// let mut r = OwnedRecord::from_std_types(b"payload".to_vec(), HashMap::new());
+/// r.set_metadata("key", "value");
+/// let raw = r.into_raw();
+/// let r = unsafe { OwnedRecord::from_raw(raw) };
+/// assert_eq!(r.content(), b"payload");
+/// assert_eq!(r.get_metadata("key").unwrap().to_str(), Ok("value"));
+/// ```
+pub struct OwnedRecord {
+    raw: Record,
+}
+
+impl OwnedRecord {
+    /// Creates a record from content (vector of bytes) and metadata (string hashmap)
+    pub fn from_std_types(content: Vec<u8>, metadata: HashMap<String, String>) -> Self {
+        OwnedRecord {
+            raw: Record::from_std_types(content, metadata),
+        }
+    }
+
+    /// Creates a record from content (vector of bytes) and typed metadata
+    pub fn from_typed_metadata(content: Vec<u8>, metadata: HashMap<String, MetadataValue>) -> Self {
+        OwnedRecord {
+            raw: Record::from_typed_metadata(content, metadata),
+        }
+    }
+
+    /// Takes ownership of a raw record
+    ///
+    /// # Safety
+    /// The record may be allocated by any library, e.g. passed by host, but its content and metadata
+    /// must carry free functions which release their memory, or no free functions if they don't own it.
+    /// The caller transfers the ownership: the record must not be used or freed anywhere else afterwards
+    pub unsafe fn from_raw(raw: Record) -> Self {
+        OwnedRecord { raw }
+    }
+
+    /// Releases ownership of record, e.g. to pass it over C ABI.
+    /// The receiving side becomes responsible for freeing the memory
+    pub fn into_raw(self) -> Record {
+        let raw = self.raw;
+        std::mem::forget(self);
+        raw
+    }
+
+    /// Returns record content without copying
+    pub fn content(&self) -> &[u8] {
+        if self.raw.content.bytes.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.raw.content.bytes, self.raw.content.len) }
+    }
+
+    /// Makes a copy of record which references the same content without copying.
+    /// The content is moved into a shared buffer on first call
+    pub fn share(&mut self) -> Self {
+        self.raw.make_content_shared();
+        OwnedRecord {
+            raw: self.raw.share(),
+        }
+    }
+}
+
+impl Deref for OwnedRecord {
+    type Target = Record;
+
+    fn deref(&self) -> &Self::Target {
+        &self.raw
+    }
+}
+
+impl DerefMut for OwnedRecord {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.raw
+    }
+}
+
+impl Clone for OwnedRecord {
+    fn clone(&self) -> Self {
+        OwnedRecord {
+            raw: self.raw.deep_copy(),
+        }
+    }
+}
+
+impl Drop for OwnedRecord {
+    fn drop(&mut self) {
+        self.raw.free_contents();
+    }
+}
+
+impl From<Vec<u8>> for OwnedRecord {
+    fn from(value: Vec<u8>) -> Self {
+        OwnedRecord::from_std_types(value, HashMap::new())
+    }
+}
+
+impl From<(Vec<u8>, HashMap<String, String>)> for OwnedRecord {
+    fn from(value: (Vec<u8>, HashMap<String, String>)) -> Self {
+        OwnedRecord::from_std_types(value.0, value.1)
+    }
+}
+
+impl From<(Vec<u8>, HashMap<String, MetadataValue>)> for OwnedRecord {
+    fn from(value: (Vec<u8>, HashMap<String, MetadataValue>)) -> Self {
+        OwnedRecord::from_typed_metadata(value.0, value.1)
+    }
+}
@@ -0,0 +1,87 @@
+//! Containers for sensitive values like passwords and access tokens
+
+use std::fmt;
+
+use zeroize::Zeroizing;
+
+use crate::error::{Error, ERROR_CODE_INVALID_PARAM};
+
+/// Placeholder printed instead of secret values
+pub const REDACTED: &str = "***";
+
+/// A string which is wiped from memory on drop and never printed.
+/// Use `expose` to access the value
+/// ```
+/// use torustiq_common::secret::SecretString;
+/// let s = SecretString::from("hunter2".to_string());
+/// assert_eq!(format!("{:?} {}", s, s), "SecretString(***) ***");
+/// assert_eq!(s.expose(), "hunter2");
+/// ```
+#[derive(Clone, Default)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        SecretString(Zeroizing::new(value))
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretString({})", REDACTED)
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", REDACTED)
+    }
+}
+
+/// Parts of param names which indicate a secret value
+const SECRET_NAME_PATTERNS: &[&str] = &["password", "passwd", "secret", "token", "api_key", "apikey", "credential", "private_key"];
+
+/// Checks if the param name looks like a name of secret, e.g. 'db_password' or 'auth_token'
+pub fn is_secret_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SECRET_NAME_PATTERNS.iter().any(|p| name.contains(p))
+}
+
+/// Resolves a reference to secret value:
+/// 'env:NAME' is replaced with the value of environment variable, 'file:PATH' is replaced with file content
+/// without the trailing line break. Other values are returned as is
+/// ```
+/// use torustiq_common::secret::resolve_secret;
+/// std::env::set_var("TORUSTIQ_DOC_TOKEN", "abc");
+/// assert_eq!(resolve_secret("token", "env:TORUSTIQ_DOC_TOKEN".to_string()).unwrap().expose(), "abc");
+/// assert_eq!(resolve_secret("token", "plain".to_string()).unwrap().expose(), "plain");
+/// assert!(resolve_secret("token", "file:/nonexistent".to_string()).is_err());
+/// ```
+pub fn resolve_secret(name: &str, value: String) -> Result<SecretString, Error> {
+    let value = SecretString::from(value);
+    if let Some(var) = value.expose().strip_prefix("env:") {
+        return std::env::var(var)
+            .map(SecretString::from)
+            .map_err(|e| resolve_error(name, format!("cannot read environment variable '{}': {}", var, e)));
+    }
+    if let Some(path) = value.expose().strip_prefix("file:") {
+        let mut content = std::fs::read_to_string(path)
+            .map_err(|e| resolve_error(name, format!("cannot read file '{}': {}", path, e)))?;
+        let len = content.trim_end_matches(['\r', '\n']).len();
+        content.truncate(len);
+        return Ok(SecretString::from(content));
+    }
+    Ok(value)
+}
+
+fn resolve_error(name: &str, msg: String) -> Error {
+    Error::config(format!("Cannot resolve the value of param '{}': {}", name, msg))
+        .with_param(name)
+        .with_code(ERROR_CODE_INVALID_PARAM)
+}
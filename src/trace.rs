@@ -0,0 +1,380 @@
+//! Trace context propagation between steps using W3C Trace Context headers stored in record metadata.
+//! Finished spans are reported to host as debug log messages and duration metrics,
+//! and passed to exporters registered with `add_exporter`
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime},
+};
+
+use log::debug;
+use once_cell::sync::Lazy;
+
+use crate::{
+    ffi::{
+        types::module::{ModuleHandle, Record},
+        utils::panic::lock_or_recover,
+    },
+    metrics::{histogram, DEFAULT_DURATION_BUCKETS},
+};
+
+/// Metadata key of the W3C trace parent header
+pub const METADATA_TRACEPARENT: &str = "traceparent";
+/// Metadata key of the W3C trace state header
+pub const METADATA_TRACESTATE: &str = "tracestate";
+
+/// The version of traceparent format written by this library
+const TRACEPARENT_VERSION: &str = "00";
+/// A version which is never valid
+const TRACEPARENT_INVALID_VERSION: u8 = 0xff;
+/// Length of traceparent header of version 00
+const TRACEPARENT_LEN: usize = 55;
+const FLAG_SAMPLED: u8 = 0x01;
+
+static EXPORTERS: Lazy<Mutex<Vec<Arc<dyn SpanExporter>>>> = Lazy::new(|| {
+    Mutex::new(Vec::new())
+});
+
+/// If false, spans are created only for records which already carry a trace context
+static ROOT_SPANS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A position in a trace: identifiers of trace and the current span
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+    /// Vendor-specific data which is passed along as is
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new trace
+    pub fn new_root() -> Self {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+        trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+        TraceContext {
+            trace_id,
+            span_id: random_u64().to_be_bytes(),
+            flags: FLAG_SAMPLED,
+            trace_state: None,
+        }
+    }
+
+    /// Returns a context of a child span in the same trace
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: random_u64().to_be_bytes(),
+            ..self.clone()
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & FLAG_SAMPLED != 0
+    }
+
+    /// Parses a traceparent header, e.g. '00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01'.
+    /// Returns None if the value is malformed, contains uppercase hex or identifiers are all zeros.
+    /// As W3C Trace Context requires, headers of future versions are parsed as version 00,
+    /// ignoring the fields appended after flags
+    /// ```
+    /// use torustiq_common::trace::TraceContext;
+    /// let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    /// let ctx = TraceContext::from_traceparent(header).unwrap();
+    /// assert!(ctx.is_sampled());
+    /// assert_eq!(ctx.to_traceparent(), header);
+    /// assert!(TraceContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+    /// assert!(TraceContext::from_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+    /// assert_eq!(TraceContext::from_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"), Some(ctx));
+    /// assert!(TraceContext::from_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+    /// ```
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let version = decode_hex(value.get(..2)?)?[0];
+        if version == TRACEPARENT_INVALID_VERSION {
+            return None;
+        }
+        // Future versions may append fields, but must keep the fields of version 00 in place
+        let value = match value.len() {
+            TRACEPARENT_LEN => value,
+            len if len > TRACEPARENT_LEN && version > 0 && value.as_bytes()[TRACEPARENT_LEN] == b'-' => &value[..TRACEPARENT_LEN],
+            _ => return None,
+        };
+        let parts: Vec<&str> = value.split('-').collect();
+        if parts.len() != 4 {
+            return None;
+        }
+        let trace_id: [u8; 16] = decode_hex(parts[1])?.try_into().ok()?;
+        let span_id: [u8; 8] = decode_hex(parts[2])?.try_into().ok()?;
+        let flags: [u8; 1] = decode_hex(parts[3])?.try_into().ok()?;
+        if trace_id.iter().all(|b| *b == 0) || span_id.iter().all(|b| *b == 0) {
+            return None;
+        }
+        Some(TraceContext { trace_id, span_id, flags: flags[0], trace_state: None })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("{}-{}-{}-{:02x}", TRACEPARENT_VERSION, encode_hex(&self.trace_id), encode_hex(&self.span_id), self.flags)
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        encode_hex(&self.span_id)
+    }
+}
+
+impl Record {
+    /// Stores trace context in metadata, so the next steps continue the same trace
+    /// ```
+    /// use std::collections::HashMap;
+    /// use torustiq_common::ffi::types::module::Record;
+    /// use torustiq_common::trace::TraceContext;
+    /// let mut r = Record::from_std_types(vec![], HashMap::new());
+    /// let mut ctx = TraceContext::new_root();
+    /// ctx.trace_state = Some("vendor=value".to_string());
+    /// r.inject_trace_context(&ctx);
+    /// assert_eq!(r.extract_trace_context(), Some(ctx));
+    /// r.free_contents();
+    /// ```
+    pub fn inject_trace_context(&mut self, ctx: &TraceContext) {
+        self.set_metadata(METADATA_TRACEPARENT, ctx.to_traceparent());
+        match &ctx.trace_state {
+            Some(s) => self.set_metadata(METADATA_TRACESTATE, s.as_str()),
+            None => {
+                self.remove_metadata(METADATA_TRACESTATE);
+            },
+        }
+    }
+
+    /// Reads trace context from metadata. Returns None if record has no valid traceparent
+    pub fn extract_trace_context(&self) -> Option<TraceContext> {
+        let traceparent = self.get_metadata(METADATA_TRACEPARENT)?.to_str().ok()?;
+        let mut ctx = TraceContext::from_traceparent(traceparent)?;
+        ctx.trace_state = self.get_metadata(METADATA_TRACESTATE)
+            .and_then(|s| s.to_str().ok())
+            .map(|s| s.to_string());
+        Some(ctx)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpanStatus {
+    Ok,
+    Error(String),
+}
+
+/// A span which is finished and reported to exporters
+#[derive(Clone, Debug)]
+pub struct FinishedSpan {
+    pub name: String,
+    pub step: ModuleHandle,
+    pub context: TraceContext,
+    /// None for root spans
+    pub parent_span_id: Option<[u8; 8]>,
+    pub start_time: SystemTime,
+    pub duration: std::time::Duration,
+    pub status: SpanStatus,
+    pub attributes: Vec<(String, String)>,
+}
+
+/// Receives finished spans
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: &FinishedSpan);
+}
+
+/// Registers an exporter of finished spans
+pub fn add_exporter(exporter: Arc<dyn SpanExporter>) {
+    lock_or_recover(&EXPORTERS).push(exporter);
+}
+
+/// Removes all exporters registered with `add_exporter`
+pub fn clear_exporters() {
+    lock_or_recover(&EXPORTERS).clear();
+}
+
+/// Enables tracing of records which have no trace context. Such records start a new trace
+pub fn set_root_spans_enabled(enabled: bool) {
+    ROOT_SPANS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// An operation in trace. The span is finished and exported when dropped
+pub struct Span {
+    name: String,
+    step: ModuleHandle,
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    start_time: SystemTime,
+    started: Instant,
+    status: SpanStatus,
+    attributes: Vec<(String, String)>,
+}
+
+impl Span {
+    /// Starts a span of step. The span is a child of the parent context, or a root of new trace
+    pub fn start<S: Into<String>>(name: S, step: ModuleHandle, parent: Option<&TraceContext>) -> Self {
+        Span {
+            name: name.into(),
+            step,
+            context: match parent {
+                Some(p) => p.child(),
+                None => TraceContext::new_root(),
+            },
+            parent_span_id: parent.map(|p| p.span_id),
+            start_time: SystemTime::now(),
+            started: Instant::now(),
+            status: SpanStatus::Ok,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Starts a span which continues the trace of record. Use `inject_trace_context` to make the span
+    /// a parent for the next steps. Returns None if the record is not sampled, or has no trace context
+    /// and root spans are disabled
+    pub fn start_for_record<S: Into<String>>(name: S, step: ModuleHandle, record: &Record) -> Option<Self> {
+        let parent = record.extract_trace_context();
+        match &parent {
+            Some(p) if !p.is_sampled() => None,
+            None if !ROOT_SPANS_ENABLED.load(Ordering::Relaxed) => None,
+            _ => Some(Span::start(name, step, parent.as_ref())),
+        }
+    }
+
+    pub fn context(&self) -> &TraceContext {
+        &self.context
+    }
+
+    pub fn set_attribute<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        self.attributes.push((key.into(), value.into()));
+    }
+
+    pub fn set_error<S: Into<String>>(&mut self, msg: S) {
+        self.status = SpanStatus::Error(msg.into());
+    }
+
+    /// Finishes the span. Same as dropping it
+    pub fn end(self) {}
+
+    fn finish(&mut self) -> FinishedSpan {
+        FinishedSpan {
+            name: std::mem::take(&mut self.name),
+            step: self.step,
+            context: self.context.clone(),
+            parent_span_id: self.parent_span_id,
+            start_time: self.start_time,
+            duration: self.started.elapsed(),
+            status: self.status.clone(),
+            attributes: std::mem::take(&mut self.attributes),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let span = self.finish();
+        debug!(target: "torustiq_common::trace", "{}", span);
+        histogram(span.step, &format!("torustiq_span_{}_duration_seconds", metric_name_part(&span.name)),
+            &format!("Duration of '{}' spans", span.name), DEFAULT_DURATION_BUCKETS)
+            .observe_duration(span.duration);
+        let exporters = lock_or_recover(&EXPORTERS).clone();
+        exporters.iter().for_each(|e| e.export(&span));
+    }
+}
+
+impl fmt::Display for FinishedSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "span '{}' trace_id={} span_id={}", self.name, self.context.trace_id_hex(), self.context.span_id_hex())?;
+        if let Some(p) = &self.parent_span_id {
+            write!(f, " parent_id={}", encode_hex(p))?;
+        }
+        write!(f, " duration={:?}", self.duration)?;
+        if let SpanStatus::Error(e) = &self.status {
+            write!(f, " error='{}'", e)?;
+        }
+        for (k, v) in &self.attributes {
+            write!(f, " {}='{}'", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps finished spans in memory. Useful in tests
+/// ```
+/// use std::sync::Arc;
+/// use torustiq_common::trace::{add_exporter, InMemoryCollector, Span};
+/// let collector = Arc::new(InMemoryCollector::default());
+/// add_exporter(collector.clone());
+/// let parent = Span::start("parent", 1, None);
+/// Span::start("child", 1, Some(parent.context())).end();
+/// parent.end();
+/// let spans = collector.take();
+/// assert_eq!(spans.len(), 2);
+/// assert_eq!(spans[0].parent_span_id, Some(spans[1].context.span_id));
+/// assert_eq!(spans[0].context.trace_id, spans[1].context.trace_id);
+/// ```
+#[derive(Default)]
+pub struct InMemoryCollector {
+    spans: Mutex<Vec<FinishedSpan>>,
+}
+
+impl InMemoryCollector {
+    /// Returns copies of collected spans
+    pub fn spans(&self) -> Vec<FinishedSpan> {
+        lock_or_recover(&self.spans).clone()
+    }
+
+    /// Returns collected spans and clears the collector
+    pub fn take(&self) -> Vec<FinishedSpan> {
+        std::mem::take(&mut *lock_or_recover(&self.spans))
+    }
+}
+
+impl SpanExporter for InMemoryCollector {
+    fn export(&self, span: &FinishedSpan) {
+        lock_or_recover(&self.spans).push(span.clone());
+    }
+}
+
+/// Returns a non-zero random number. Uses the randomly seeded hasher of std to avoid extra dependencies
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Ok(d) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(d.as_nanos());
+        }
+        let v = hasher.finish();
+        if v != 0 {
+            return v;
+        }
+    }
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes lowercase hex as required by traceparent format
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c)) {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Replaces characters which are not allowed in metric names
+fn metric_name_part(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect()
+}